@@ -2,13 +2,36 @@ use std::vec;
 
 use cosmwasm_std::{
     entry_point, Addr, Deps, DepsMut, Env, MessageInfo, Response,
-    StdResult, Uint128, StdError, from_binary, Storage, WasmMsg, to_binary, CosmosMsg
+    StdResult, Uint128, StdError, from_binary, Storage, WasmMsg, to_binary, CosmosMsg, Order,
+    BankMsg, coins, Binary, Decimal, Uint256
 };
+use cw_storage_plus::Bound;
+use cw2::{get_contract_version, set_contract_version};
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg};
 use cw721::Cw721ReceiveMsg;
 
 use crate::error::ContractError;
-use crate::msg::{ ExecuteMsg, InstantiateMsg, CreateCollectionPoolMsg, UpdateCollectionPoolMsg, UpdateContractInfoMsg, DepositeMsg};
-use crate::state::{ContractInfo, CONTRACT_INFO, COLLECTION_POOL_INFO, STAKING_INFO, CollectionPoolInfo, StakerInfo, CollectionStakedTokenInfo};
+use crate::msg::{
+    ExecuteMsg, InstantiateMsg, NftId, MigrateMsg, UpdateContractInfoMsg, CreateCollectionPoolMsg, UpdateCollectionPoolMsg, DepositeMsg, RewardAssetInfo, EarlyWithdrawPenaltyMsg, IncentiveAssetInfo, TokenWeight, SetRequirementMsg, Cw20HookMsg,
+    QueryMsg, ContractInfoResponse, PauseInfoResponse, RoleHoldersResponse, WhitelistResponse, CollectionPoolResponse, ListCollectionPoolsResponse, SetBonusesResponse, TokenWeightResponse, StakerInfoResponse, StakedTokenTiersResponse, StakedTokenTier, StreamPendingReward, PendingRewardResponse
+};
+use crate::state::{
+    ContractInfo, CONTRACT_INFO, LEGACY_CONTRACT_INFO, ROLES, Role, NFT_721_CONTRACT_WHITELIST, CONTRACT_PAUSE_INFO, COLLECTION_PAUSE_INFO, PausableAction, COLLECTION_POOL_INFO, STAKING_INFO, LEGACY_STAKING_INFO, LEGACY_COLLECTION_POOL_INFO, TOKEN_WEIGHTS, DEFAULT_TOKEN_WEIGHT, STAKE_PRECISION, SET_BONUSES,
+    CollectionPoolInfo, StakerInfo, CollectionStakedTokenInfo, RewardAsset, EmissionMode, RewardScheduleEntry, EarlyWithdrawPenalty, SetBonus, SetRequirement, LoyaltyTier, RewardStream, StakerStreamReward, IncentiveAsset, ACC_PER_SHARE_PRECISION
+};
+
+const CONTRACT_NAME: &str = "crates.io:nft-staking";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const DEFAULT_MIGRATION_LIMIT: u32 = 30;
+const DEFAULT_QUERY_LIMIT: u32 = 10;
+const MAX_QUERY_LIMIT: u32 = 30;
+
+/// Transfers to send along with the attributes describing them
+type TransfersWithAttributes = (Vec<CosmosMsg>, Vec<(&'static str, String)>);
+
+/// Reward amounts already committed to transfers within the current message, per asset
+type SentRewards = Vec<(RewardAsset, Uint128)>;
 
 #[entry_point]
 pub fn instantiate(
@@ -17,18 +40,35 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
-    let mut admin = info.sender.to_string();
+    let mut admin = info.sender.clone();
 
-    if msg.admin.is_some() {
-        admin = msg.admin.unwrap()
+    if let Some(msg_admin) = msg.admin.as_ref() {
+        admin = deps.api.addr_validate(msg_admin)?;
     }
 
+    let default_reward_asset = match msg.default_reward_asset {
+        Some(reward_asset) => Some(validate_reward_asset(deps.as_ref(), reward_asset)?),
+        None => None,
+    };
+    let fee_collector = match msg.fee_collector.as_ref() {
+        Some(fee_collector) => Some(deps.api.addr_validate(fee_collector)?),
+        None => None,
+    };
+    let incentive_assets = validate_incentive_assets(deps.as_ref(), msg.incentive_assets.unwrap_or_default())?;
+
     let config = ContractInfo {
         source: info.sender,
+        start_height: msg.start_height,
+        start_time: msg.start_time,
         end_height: msg.end_height,
         end_time: msg.end_time,
         admin: Some(admin),
-        nft_721_contract_addr_whitelist: msg.nft_721_contract_addr_whitelist,
+        pending_admin: None,
+        default_reward_asset,
+        fee_collector,
+        max_pools: msg.max_pools,
+        incentive_assets,
+        max_incentive_streams: msg.max_incentive_streams,
     };
 
     if config.is_expired(&env) {
@@ -38,10 +78,242 @@ pub fn instantiate(
         });
     }
 
+    for addr in msg.nft_721_contract_addr_whitelist.iter() {
+        NFT_721_CONTRACT_WHITELIST.save(deps.storage, &deps.api.addr_validate(addr)?, &())?;
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     CONTRACT_INFO.save(deps.storage, &config)?;
     Ok(Response::default())
 }
 
+#[entry_point]
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    // Deployments from before version tracking keep their ContractInfo where cw2 stores the version
+    let previous_version = get_contract_version(deps.storage).ok();
+
+    if let Some(previous_version) = previous_version.as_ref() {
+        if previous_version.contract != CONTRACT_NAME {
+            return Err(ContractError::CannotMigrate {
+                previous_contract: previous_version.contract.clone(),
+            });
+        }
+        if !is_older_version(&previous_version.version, CONTRACT_VERSION) {
+            return Err(ContractError::CannotMigrateVersion {
+                previous_version: previous_version.version.clone(),
+                new_version: CONTRACT_VERSION.to_string(),
+            });
+        }
+    } else {
+        migrate_from_unversioned(deps.branch(), &env, msg)?;
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", previous_version.map(|v| v.version).unwrap_or_else(|| "unversioned".to_string()))
+        .add_attribute("to_version", CONTRACT_VERSION)
+    )
+}
+
+/// Compares dotted numeric versions, ignoring pre-release and build suffixes.
+/// Versions that can't be parsed are never older.
+fn is_older_version(version: &str, than: &str) -> bool {
+    let parse = |version: &str| -> Option<Vec<u64>> {
+        version
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    };
+
+    match (parse(version), parse(than)) {
+        (Some(version), Some(than)) => version < than,
+        _ => false,
+    }
+}
+
+/// Rewrites the layout used before cw2 version tracking: the contract info and whitelist,
+/// collection pools without reward assets or a scaled accumulator, and stakers keyed by wallet only.
+fn migrate_from_unversioned(mut deps: DepsMut, env: &Env, msg: MigrateMsg) -> Result<(), ContractError> {
+    let legacy_contract_info = LEGACY_CONTRACT_INFO.load(deps.storage)?;
+
+    let admin = match legacy_contract_info.admin {
+        Some(admin) => Some(deps.api.addr_validate(&admin)?),
+        None => None,
+    };
+    for addr in legacy_contract_info.nft_721_contract_addr_whitelist.iter() {
+        NFT_721_CONTRACT_WHITELIST.save(deps.storage, &deps.api.addr_validate(addr)?, &())?;
+    }
+
+    LEGACY_CONTRACT_INFO.remove(deps.storage);
+    // The legacy end_height/end_time were escrow leftovers that only gated instantiation,
+    // they are not carried over as the end of the staking program
+    CONTRACT_INFO.save(deps.storage, &ContractInfo {
+        source: legacy_contract_info.source,
+        start_height: None,
+        start_time: None,
+        end_height: None,
+        end_time: None,
+        admin,
+        pending_admin: None,
+        default_reward_asset: None,
+        fee_collector: None,
+        max_pools: None,
+        incentive_assets: vec![],
+        max_incentive_streams: None,
+    })?;
+
+    for pool in msg.legacy_collection_pools.into_iter() {
+        migrate_legacy_collection_pool(deps.branch(), &pool.collection_id, pool.reward_asset, pool.nft_721_contract_addrs)?;
+    }
+
+    for collection_id in msg.legacy_staker_collection_ids.iter() {
+        migrate_legacy_stakers(deps.storage, env, collection_id, None)?;
+    }
+
+    Ok(())
+}
+
+fn migrate_legacy_collection_pool(
+    deps: DepsMut,
+    collection_id: &str,
+    reward_asset: RewardAssetInfo,
+    nft_721_contract_addrs: Vec<String>,
+) -> Result<(), ContractError> {
+    let nft_721_contract_addrs = validate_nft_721_contract_addrs(deps.as_ref(), nft_721_contract_addrs)?;
+
+    let legacy_info = LEGACY_COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+    if legacy_info.is_none() {
+        return Err(ContractError::InvalidCollection {});
+    }
+    let legacy_info = legacy_info.unwrap();
+
+    if COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+        return Err(ContractError::Std(StdError::generic_err(
+            "Collection info already existed",
+        )));
+    }
+
+    let collection_pool_info = CollectionPoolInfo {
+        collection_id: legacy_info.collection_id,
+        nft_721_contract_addrs,
+        reward_per_block: legacy_info.reward_per_block,
+        reward_schedule: vec![],
+        emission_mode: EmissionMode::Block,
+        reward_per_second: Uint128::from(0u128),
+        start_time: None,
+        end_time: None,
+        last_reward_time: 0u64,
+        total_nfts: legacy_info.total_nfts.checked_mul(STAKE_PRECISION).map_err(StdError::from)?,
+        // Staker reward debts stay valid, they were stored unscaled against the unscaled accumulator and a stake of one per nft
+        acc_per_share: legacy_info.acc_per_share
+            .checked_mul(ACC_PER_SHARE_PRECISION / STAKE_PRECISION)
+            .map_err(StdError::from)?,
+        last_reward_block: legacy_info.last_reward_block,
+        expired_block: legacy_info.expired_block,
+        min_lock_blocks: 0u64,
+        early_withdraw_penalty: None,
+        loyalty_tiers: vec![],
+        reward_streams: vec![],
+        next_reward_stream_id: 0u64,
+        reward_asset: validate_reward_asset(deps.as_ref(), reward_asset)?,
+        reward_balance: Uint128::from(0u128),
+        total_distributed: Uint128::from(0u128),
+    };
+
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+    LEGACY_COLLECTION_POOL_INFO.remove(deps.storage, collection_id.as_bytes());
+    Ok(())
+}
+
+/// Moves the nfts of up to `limit` staker records keyed by wallet only into `collection_id`, returning how many records were touched.
+/// Legacy records mixed nfts of several pools, so only nfts from contracts bound to `collection_id` are moved,
+/// the rest stay behind for the pools bound to their contracts.
+fn migrate_legacy_stakers(
+    storage: &mut dyn Storage,
+    env: &Env,
+    collection_id: &str,
+    limit: Option<usize>,
+) -> Result<usize, ContractError> {
+    if !COLLECTION_POOL_INFO.has(storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
+    }
+    let mut collection_pool_info = update_collection_pool(storage, env.clone(), collection_id.to_string())?;
+
+    let legacy_stakers = LEGACY_STAKING_INFO
+        .range(storage, None, None, Order::Ascending)
+        .filter(|item| match item {
+            Ok((_, legacy_info)) => legacy_info
+                .staked_tokens
+                .iter()
+                .any(|token| collection_pool_info.nft_721_contract_addrs.contains(&token.contract_addr)),
+            Err(..) => true,
+        })
+        .take(limit.unwrap_or(usize::MAX))
+        .collect::<StdResult<Vec<(Vec<u8>, StakerInfo)>>>()?;
+
+    let migrated_stakers = legacy_stakers.len();
+    for (staker, mut legacy_info) in legacy_stakers.into_iter() {
+        let (moved_tokens, left_tokens): (Vec<CollectionStakedTokenInfo>, Vec<CollectionStakedTokenInfo>) = legacy_info
+            .staked_tokens
+            .into_iter()
+            .partition(|token| collection_pool_info.nft_721_contract_addrs.contains(&token.contract_addr));
+        let moved_nfts = Uint128::from(moved_tokens.len() as u128);
+        let moved_stake = moved_nfts * STAKE_PRECISION;
+        // Legacy nfts carry no staking block, loyalty tiers count from the migration
+        let moved_tokens = moved_tokens
+            .into_iter()
+            .map(|token| CollectionStakedTokenInfo { staked_at: env.block.height, ..token })
+            .collect::<Vec<CollectionStakedTokenInfo>>();
+
+        // Legacy nfts were counted once each, a pool that doesn't hold them can't take them over
+        collection_pool_info.total_nfts = collection_pool_info
+            .total_nfts
+            .checked_sub(moved_stake)
+            .map_err(|_| ContractError::LegacyStakeMismatch { collection_id: collection_id.to_string() })?;
+
+        // The reward debt is only meaningful against a single pool, unsettled rewards of mixed records can't be attributed.
+        // A split record keeps its original total_staked, so its other parts are recognized as mixed too.
+        let mut carried_pending = legacy_info.pending;
+        if left_tokens.is_empty() && legacy_info.total_staked == moved_nfts {
+            carried_pending += accumulated_reward(moved_stake, collection_pool_info.acc_per_share)
+                .saturating_sub(legacy_info.reward_debt);
+        }
+
+        // Merge with anything the staker already deposited into this pool after the upgrade
+        let mut staker_info = STAKING_INFO
+            .may_load(storage, (collection_id.as_bytes(), staker.as_slice()))?
+            .unwrap_or(StakerInfo {
+                total_staked: Uint128::from(0u128),
+                reward_debt: Uint128::from(0u128),
+                pending: Uint128::from(0u128),
+                total_earned: Uint128::from(0u128),
+                staked_tokens: vec![],
+                set_bonuses: vec![],
+                stream_rewards: vec![],
+            });
+        staker_info.pending = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share) - staker_info.reward_debt + staker_info.pending + carried_pending;
+        settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+        staker_info.staked_tokens.extend(moved_tokens);
+        refresh_staker_stake(storage, env.block.height, &mut collection_pool_info, &mut staker_info)?;
+        STAKING_INFO.save(storage, (collection_id.as_bytes(), staker.as_slice()), &staker_info)?;
+
+        if left_tokens.is_empty() {
+            LEGACY_STAKING_INFO.remove(storage, staker.as_slice());
+        } else {
+            legacy_info.staked_tokens = left_tokens;
+            legacy_info.pending = Uint128::from(0u128);
+            LEGACY_STAKING_INFO.save(storage, staker.as_slice(), &legacy_info)?;
+        }
+    }
+
+    COLLECTION_POOL_INFO.save(storage, collection_id.as_bytes(), &collection_pool_info)?;
+    Ok(migrated_stakers)
+}
+
 #[entry_point]
 pub fn execute(
     deps: DepsMut,
@@ -50,48 +322,63 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::UpdateContractInfo ( msg ) => try_update_contract_info(deps, info, msg),
+        ExecuteMsg::UpdateContractInfo(msg) => try_update_contract_info(deps, info, msg),
+        ExecuteMsg::ProposeNewAdmin { new_admin } => try_propose_new_admin(deps, info, new_admin),
+        ExecuteMsg::AcceptAdmin {} => try_accept_admin(deps, info),
+        ExecuteMsg::CancelAdminProposal {} => try_cancel_admin_proposal(deps, info),
+        ExecuteMsg::RenounceAdmin {} => try_renounce_admin(deps, info),
+        ExecuteMsg::GrantRole { role, address } => try_grant_role(deps, info, role, address),
+        ExecuteMsg::RevokeRole { role, address } => try_revoke_role(deps, info, role, address),
+        ExecuteMsg::Pause { collection_id, actions } => try_set_paused(deps, info, collection_id, actions, true),
+        ExecuteMsg::Unpause { collection_id, actions } => try_set_paused(deps, info, collection_id, actions, false),
+        ExecuteMsg::AddToWhitelist { contract_addrs } => try_add_to_whitelist(deps, info, contract_addrs),
+        ExecuteMsg::RemoveFromWhitelist { contract_addrs } => try_remove_from_whitelist(deps, info, contract_addrs),
         ExecuteMsg::CreateCollectionPool(msg) => try_create_collection_pool_info(deps, env, info, msg),
-        ExecuteMsg::UpdateCollectionPool(msg) => try_update_collection_pool_info(deps, info, msg),
+        ExecuteMsg::UpdateCollectionPool(msg) => try_update_collection_pool_info(deps, env, info, msg),
+        ExecuteMsg::AddSetBonus { collection_id, set_id, requirement, multiplier } => try_add_set_bonus(deps, info, collection_id, set_id, requirement, multiplier),
+        ExecuteMsg::RemoveSetBonus { collection_id, set_id } => try_remove_set_bonus(deps, info, collection_id, set_id),
+        ExecuteMsg::AddRewardStream { collection_id, reward_asset, reward_per_block, end_block } => try_add_reward_stream(deps, env, info, collection_id, reward_asset, reward_per_block, end_block),
+        ExecuteMsg::SetTokenWeights { collection_id, weights } => try_set_token_weights(deps, info, collection_id, weights),
         ExecuteMsg::ReceiveNft(receive_msg) => try_receive_721(deps, env, info, receive_msg),
         ExecuteMsg::Withdraw { collection_id, withdraw_rewards, withdraw_nft_ids } => try_withdraw(deps, env, info, collection_id, withdraw_rewards, withdraw_nft_ids),
-        // ExecuteMsg::Claim { collection_id } => todo!(),
-        // ExecuteMsg::Refund {  } => todo!(),
+        ExecuteMsg::MigrateCollectionPool { collection_id, reward_asset, nft_721_contract_addrs } => try_migrate_collection_pool(deps, info, collection_id, reward_asset, nft_721_contract_addrs),
+        ExecuteMsg::MigrateStakerInfo { collection_id, limit } => try_migrate_staker_info(deps, env, info, collection_id, limit),
+        ExecuteMsg::RefreshStake { collection_id, staker } => try_refresh_stake(deps, env, info, collection_id, staker),
+        ExecuteMsg::Claim { collection_id } => try_claim(deps, env, info, collection_id),
+        ExecuteMsg::EmergencyWithdraw { collection_id } => try_emergency_withdraw(deps, env, info, collection_id),
+        ExecuteMsg::FundPool { collection_id } => try_fund_pool(deps, env, info, collection_id),
+        ExecuteMsg::CreateIncentiveStream { collection_id, reward_per_block, duration_blocks } => try_create_native_incentive_stream(deps, env, info, collection_id, reward_per_block, duration_blocks),
+        ExecuteMsg::RefundEndedIncentiveStreams { collection_id } => try_refund_ended_incentive_streams(deps, env, collection_id),
+        ExecuteMsg::FundRewardStream { collection_id, stream_id } => try_fund_reward_stream(deps, env, info, collection_id, stream_id),
+        ExecuteMsg::Receive(receive_msg) => try_receive_cw20(deps, env, info, receive_msg),
+        ExecuteMsg::ReclaimUnallocatedRewards { collection_id, recipient } => try_reclaim_unallocated_rewards(deps, env, info, collection_id, recipient),
+        ExecuteMsg::ReclaimUnallocatedStreamRewards { collection_id, stream_id, recipient } => try_reclaim_unallocated_stream_rewards(deps, env, info, collection_id, stream_id, recipient),
     }
 }
 
 fn try_withdraw(
-    deps: DepsMut, 
+    mut deps: DepsMut, 
     env: Env, 
     info: MessageInfo, 
     collection_id: String, 
     withdraw_rewards: bool, 
-    withdraw_nft_ids: Vec<String>
+    withdraw_nft_ids: Vec<NftId>
 ) -> Result<Response, ContractError> {
-    let staker_info = STAKING_INFO.load(deps.storage, &info.sender.clone().as_bytes())?;
+    // EmergencyWithdraw stays open as the exit while withdrawals are paused
+    check_not_paused(deps.storage, &collection_id, PausableAction::Withdraw)?;
+    if withdraw_rewards {
+        check_not_paused(deps.storage, &collection_id, PausableAction::Claim)?;
+    }
 
-    let collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
-    let current_pending = staker_info.total_staked * collection_pool_info.acc_per_share - staker_info.reward_debt;
+    let mut staker_info = STAKING_INFO.load(deps.storage, (collection_id.as_bytes(), info.sender.as_bytes()))?;
+    let withdraw_nft_ids = withdraw_nft_ids
+        .into_iter()
+        .map(|nft_id| Ok((deps.api.addr_validate(&nft_id.contract_addr)?, nft_id.token_id)))
+        .collect::<StdResult<Vec<(Addr, String)>>>()?;
 
-    if current_pending.gt(&Uint128::from(0u128)) {
-        STAKING_INFO.update(
-            deps.storage,
-            &info.sender.clone().as_bytes(),
-            |data| {
-                if let Some(mut old_info) = data {
-                    if withdraw_rewards {
-                        old_info.total_earned += current_pending;
-                        old_info.pending = Uint128::from(0u128)
-                    } else {
-                        old_info.pending = current_pending;
-                    }
-                    Ok(old_info)
-                } else {
-                    Err(ContractError::Std(StdError::generic_err("Invalid update staker")))
-                }
-            }
-        )?;
-    }
+    let mut sent_rewards: SentRewards = vec![];
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps.branch(), &env, &mut sent_rewards, &collection_id)?;
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
 
     let mut withdraw_nfts = vec![];
     let mut left_nfts = vec![];
@@ -102,9 +389,8 @@ fn try_withdraw(
         .into_iter()
         .for_each(|token| {
             let res = withdraw_nft_ids
-                .clone()
-                .into_iter()
-                .find(|n| n.eq(&token.token_id.clone()));
+                .iter()
+                .find(|(contract_addr, token_id)| contract_addr.eq(&token.contract_addr) && token_id.eq(&token.token_id));
             match res {
                 Some(..) => withdraw_nfts.push(token.clone()),
                 None => left_nfts.push(token.clone()),
@@ -115,13 +401,49 @@ fn try_withdraw(
         return Err(ContractError::Std(StdError::generic_err("Invalid withdraw:  You are trying to withdraw some nfts that you haven't staken!")))
     }
 
-    let mut num_of_withdraw_edition = Uint128::from(0u128);
+    check_nfts_unlocked(&env, &withdraw_nfts, collection_pool_info.min_lock_blocks)?;
+
+    let mut current_pending = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share) - staker_info.reward_debt + staker_info.pending;
+
+    // Withdrawing any nft before its penalty period ends forfeits part of the pending rewards, as does paying them
+    // out while any other nft is still within it
+    let penalized_nfts = if withdraw_rewards { &staker_info.staked_tokens } else { &withdraw_nfts };
+    let mut penalty = Uint128::from(0u128);
+    if let Some(penalty_rate) = early_withdraw_penalty_rate(&env, &collection_pool_info, penalized_nfts) {
+        penalty = current_pending * penalty_rate;
+        current_pending -= penalty;
+    }
 
     let mut cosmos_msgs: Vec<CosmosMsg> = vec![];
+    let mut claimed_rewards = Uint128::from(0u128);
+
+    if withdraw_rewards && current_pending.gt(&Uint128::from(0u128)) {
+        cosmos_msgs.push(build_reward_transfer_msg(
+            deps.as_ref(),
+            &env,
+            &mut sent_rewards,
+            &collection_pool_info.reward_asset,
+            &info.sender,
+            current_pending,
+        )?);
+        claimed_rewards = current_pending;
+    }
+
+    settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+    let mut stream_attrs = vec![];
+    if withdraw_rewards {
+        let (stream_msgs, attrs) = pay_reward_streams(deps.as_ref(), &env, &mut sent_rewards, &collection_pool_info, &mut staker_info, &info.sender)?;
+        cosmos_msgs.extend(stream_msgs);
+        stream_attrs = attrs;
+    }
+
+    let mut num_of_withdraw_edition = Uint128::from(0u128);
+    let mut withdraw_weight = Uint128::from(0u128);
 
     // Transfer nfts back to staker
     for nft in withdraw_nfts {
         num_of_withdraw_edition += Uint128::from(1u128);
+        withdraw_weight += nft.weight;
         cosmos_msgs.push(
             WasmMsg::Execute { 
                 contract_addr: nft.contract_addr.to_string(), 
@@ -134,37 +456,42 @@ fn try_withdraw(
         );
     }
 
-    STAKING_INFO.update(
-        deps.storage,
-        &info.sender.clone().as_bytes(),
-        |data| {
-            if let Some(mut old_info) = data {
-                old_info.total_staked = old_info.total_staked - num_of_withdraw_edition;
-                old_info.reward_debt = old_info.total_staked * collection_pool_info.acc_per_share;
-                old_info.staked_tokens = left_nfts;
-                Ok(old_info)
-            } else {
-                Err(ContractError::Std(StdError::generic_err("Invalid update staker info")))
-            }
-        }
-    )?;
+    let (stake, set_bonuses) = compute_staker_stake(deps.storage, &collection_pool_info, env.block.height, &left_nfts)?;
+    collection_pool_info.total_nfts = collection_pool_info.total_nfts - staker_info.total_staked + stake;
 
-    COLLECTION_POOL_INFO.update(
-        deps.storage,
-        collection_pool_info.collection_id.as_bytes(),
-        |data| {
-            if let Some(mut old_info) = data {
-                old_info.total_nfts = old_info.total_nfts - num_of_withdraw_edition;
-                Ok(old_info)
-            } else {
-                return Err(ContractError::Std(StdError::generic_err("Invalid update collection pool info")));
-            }
-        }
-    )?;
+    let (penalty_msg, penalty_recipient) = pay_early_withdraw_penalty(deps.as_ref(), &env, &mut sent_rewards, &mut collection_pool_info, penalty, stake)?;
+    cosmos_msgs.extend(penalty_msg);
+
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    let previous_set_bonuses = staker_info.set_bonuses.clone();
+    if withdraw_rewards {
+        staker_info.total_earned += current_pending;
+        staker_info.pending = Uint128::from(0u128);
+    } else {
+        staker_info.pending = current_pending;
+    }
+    staker_info.total_staked = stake;
+    // Taken after the penalty was split so the staker's remaining nfts don't earn from it
+    staker_info.reward_debt = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share);
+    reset_reward_stream_debts(&collection_pool_info, &mut staker_info);
+    staker_info.staked_tokens = left_nfts;
+    staker_info.set_bonuses = set_bonuses;
+    STAKING_INFO.save(deps.storage, (collection_id.as_bytes(), info.sender.as_bytes()), &staker_info)?;
 
     Ok(Response::new()
         .add_messages(cosmos_msgs)
-        .add_attribute("action", "stake nfts")
+        .add_messages(refund_msgs)
+        .add_attribute("action", "withdraw")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("withdraw_nfts", num_of_withdraw_edition)
+        .add_attribute("withdraw_weight", withdraw_weight)
+        .add_attribute("claimed_rewards", claimed_rewards)
+        .add_attribute("penalty", penalty)
+        .add_attribute("penalty_recipient", penalty_recipient)
+        .add_attributes(stream_attrs)
+        .add_attributes(refund_attrs)
+        .add_attributes(set_bonus_attributes(&previous_set_bonuses, &staker_info.set_bonuses))
     )
 
     // match staker_info {
@@ -180,25 +507,234 @@ fn try_withdraw(
     // }
 }
 
+fn try_emergency_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+) -> Result<Response, ContractError> {
+    let staker_info = STAKING_INFO.load(deps.storage, (collection_id.as_bytes(), info.sender.as_bytes()))?;
+
+    if staker_info.staked_tokens.is_empty() {
+        return Err(ContractError::Std(StdError::generic_err("You have not staked any nfts")));
+    }
+
+    let collection_pool_info = COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+    if collection_pool_info.is_none() {
+        return Err(ContractError::InvalidCollection {});
+    }
+    let collection_pool_info = collection_pool_info.unwrap();
+
+    // Pausing withdrawals leaves this as the only exit, which then also releases locked nfts
+    if check_not_paused(deps.storage, &collection_id, PausableAction::Withdraw).is_ok() {
+        check_nfts_unlocked(&env, &staker_info.staked_tokens, collection_pool_info.min_lock_blocks)?;
+    }
+
+    // Transfer every nft back to staker, nothing below fails on broken reward math
+    let mut cosmos_msgs: Vec<CosmosMsg> = vec![];
+    for nft in staker_info.staked_tokens.iter() {
+        cosmos_msgs.push(
+            WasmMsg::Execute {
+                contract_addr: nft.contract_addr.to_string(),
+                msg: to_binary(&cw721::Cw721ExecuteMsg::TransferNft {
+                    recipient: info.sender.to_string(),
+                    token_id: nft.token_id.clone(),
+                })?,
+                funds: vec![]
+            }.into()
+        );
+    }
+
+    // Accrue at the old stake so the remaining stakers keep their share up to now. Only when the reward math
+    // overflows are the blocks since the last update skipped instead, leaving their rewards in the budget.
+    let mut collection_pool_info = match simulate_collection_pool(&env, collection_pool_info.clone()) {
+        Ok(updated_collection_pool_info) => updated_collection_pool_info,
+        Err(..) => {
+            let mut collection_pool_info = collection_pool_info;
+            collection_pool_info.last_reward_block = collection_pool_info.last_reward_block.max(env.block.height);
+            collection_pool_info.last_reward_time = collection_pool_info.last_reward_time.max(env.block.time.seconds());
+            apply_reward_schedule(&mut collection_pool_info);
+            for stream in collection_pool_info.reward_streams.iter_mut() {
+                stream.last_reward_block = stream.last_reward_block.max(env.block.height);
+            }
+            collection_pool_info
+        }
+    };
+
+    let forfeited_rewards = forfeit_staker_rewards(&mut collection_pool_info, &staker_info);
+    let withdraw_weight = staker_info
+        .staked_tokens
+        .iter()
+        .fold(Uint128::from(0u128), |total, nft| total + nft.weight);
+    collection_pool_info.total_nfts = collection_pool_info.total_nfts.saturating_sub(staker_info.total_staked);
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    STAKING_INFO.update(
+        deps.storage,
+        (collection_id.as_bytes(), info.sender.as_bytes()),
+        |data| {
+            if let Some(mut old_info) = data {
+                old_info.total_staked = Uint128::from(0u128);
+                old_info.reward_debt = Uint128::from(0u128);
+                old_info.pending = Uint128::from(0u128);
+                old_info.staked_tokens = vec![];
+                old_info.set_bonuses = vec![];
+                old_info.stream_rewards = vec![];
+                Ok(old_info)
+            } else {
+                Err(ContractError::Std(StdError::generic_err("Invalid update staker info")))
+            }
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_messages(cosmos_msgs)
+        .add_attribute("action", "emergency_withdraw")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("staker", info.sender)
+        .add_attribute("withdraw_nfts", staker_info.staked_tokens.len().to_string())
+        .add_attribute("withdraw_weight", withdraw_weight)
+        .add_attribute("forfeited_rewards", forfeited_rewards)
+        .add_attributes(set_bonus_attributes(&staker_info.set_bonuses, &[]))
+    )
+}
+
+fn try_claim(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+) -> Result<Response, ContractError> {
+    check_not_paused(deps.storage, &collection_id, PausableAction::Claim)?;
+
+    let staker_info = STAKING_INFO.may_load(deps.storage, (collection_id.as_bytes(), info.sender.as_bytes()))?;
+    if staker_info.is_none() {
+        return Err(ContractError::NoPendingRewards {});
+    }
+    let mut staker_info = staker_info.unwrap();
+
+    let mut sent_rewards: SentRewards = vec![];
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps.branch(), &env, &mut sent_rewards, &collection_id)?;
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+    let mut current_pending = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share) - staker_info.reward_debt + staker_info.pending;
+
+    // Claiming ahead of an early withdrawal doesn't escape its penalty
+    let mut penalty = Uint128::from(0u128);
+    if let Some(penalty_rate) = early_withdraw_penalty_rate(&env, &collection_pool_info, &staker_info.staked_tokens) {
+        penalty = current_pending * penalty_rate;
+        current_pending -= penalty;
+    }
+
+    settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+    let (mut cosmos_msgs, stream_attrs) = pay_reward_streams(deps.as_ref(), &env, &mut sent_rewards, &collection_pool_info, &mut staker_info, &info.sender)?;
+
+    if current_pending.is_zero() && penalty.is_zero() && cosmos_msgs.is_empty() {
+        return Err(ContractError::NoPendingRewards {});
+    }
+
+    if current_pending.gt(&Uint128::from(0u128)) {
+        cosmos_msgs.push(build_reward_transfer_msg(
+            deps.as_ref(),
+            &env,
+            &mut sent_rewards,
+            &collection_pool_info.reward_asset,
+            &info.sender,
+            current_pending,
+        )?);
+    }
+    let (penalty_msg, penalty_recipient) = pay_early_withdraw_penalty(deps.as_ref(), &env, &mut sent_rewards, &mut collection_pool_info, penalty, staker_info.total_staked)?;
+    cosmos_msgs.extend(penalty_msg);
+
+    staker_info.total_earned += current_pending;
+    staker_info.pending = Uint128::from(0u128);
+    // Pick up loyalty tiers reached since the last recalculation
+    let set_bonus_attrs = refresh_staker_stake(deps.storage, env.block.height, &mut collection_pool_info, &mut staker_info)?;
+
+    STAKING_INFO.save(deps.storage, (collection_id.as_bytes(), info.sender.as_bytes()), &staker_info)?;
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_messages(cosmos_msgs)
+        .add_messages(refund_msgs)
+        .add_attribute("action", "claim")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("staker", info.sender)
+        .add_attribute("claimed_rewards", current_pending)
+        .add_attribute("penalty", penalty)
+        .add_attribute("penalty_recipient", penalty_recipient)
+        .add_attributes(stream_attrs)
+        .add_attributes(refund_attrs)
+        .add_attributes(set_bonus_attrs)
+    )
+}
+
+fn try_refresh_stake(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+    staker: Option<String>,
+) -> Result<Response, ContractError> {
+    let staker = match staker {
+        Some(staker) => deps.api.addr_validate(&staker)?,
+        None => info.sender,
+    };
+
+    let mut staker_info = STAKING_INFO.load(deps.storage, (collection_id.as_bytes(), staker.as_bytes()))?;
+    let mut sent_rewards: SentRewards = vec![];
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps.branch(), &env, &mut sent_rewards, &collection_id)?;
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+
+    // Settle at the old stake before it changes
+    staker_info.pending = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share) - staker_info.reward_debt + staker_info.pending;
+    settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+    let set_bonus_attrs = refresh_staker_stake(deps.storage, env.block.height, &mut collection_pool_info, &mut staker_info)?;
+
+    STAKING_INFO.save(deps.storage, (collection_id.as_bytes(), staker.as_bytes()), &staker_info)?;
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_messages(refund_msgs)
+        .add_attribute("action", "refresh_stake")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("staker", staker)
+        .add_attribute("stake", staker_info.total_staked)
+        .add_attributes(set_bonus_attrs)
+        .add_attributes(refund_attrs)
+    )
+}
+
 fn try_receive_721(
-    deps: DepsMut, 
+    mut deps: DepsMut, 
     env: Env, 
     info: MessageInfo, 
     receive_msg: Cw721ReceiveMsg
 ) -> Result<Response, ContractError> {
-    let contract_info = CONTRACT_INFO.load(deps.storage)?;
-
-    let result = contract_info
-        .nft_721_contract_addr_whitelist
-        .into_iter()
-        .find(|addr| addr.eq(&info.sender.to_string()));
-
-    if result.is_none() {
+    if !NFT_721_CONTRACT_WHITELIST.has(deps.storage, &info.sender) {
         return Err(ContractError::Unauthorized { sender: info.sender.to_string() });
     }
 
     let deposit_msg = from_binary::<DepositeMsg>(&receive_msg.msg)?;
 
+    check_not_paused(deps.storage, &deposit_msg.collection_id, PausableAction::Deposit)?;
+
+    let contract_info = CONTRACT_INFO.load(deps.storage)?;
+    if !contract_info.is_started(&env) {
+        return Err(ContractError::NotStarted {
+            start_height: contract_info.start_height,
+            start_time: contract_info.start_time,
+        });
+    }
+    if contract_info.is_expired(&env) {
+        return Err(ContractError::Expired {
+            end_height: contract_info.end_height,
+            end_time: contract_info.end_time,
+        });
+    }
+    if deposit_msg.withdraw_rewards {
+        check_not_paused(deps.storage, &deposit_msg.collection_id, PausableAction::Claim)?;
+    }
+
     let collection_pool_info 
         = COLLECTION_POOL_INFO.may_load(deps.storage, deposit_msg.collection_id.clone().as_bytes()).unwrap();
     
@@ -208,19 +744,56 @@ fn try_receive_721(
 
     check_collection_is_expired(env.clone(), &collection_pool_info.clone().unwrap())?;
 
+    // The nft must come from one of the contracts bound to the pool it is staked into
+    if !collection_pool_info.unwrap().nft_721_contract_addrs.contains(&info.sender) {
+        return Err(ContractError::InvalidCollectionNftContract {
+            collection_id: deposit_msg.collection_id,
+            contract_addr: info.sender.to_string(),
+        });
+    }
 
     // staking process...
+    let mut sent_rewards: SentRewards = vec![];
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps.branch(), &env, &mut sent_rewards, &deposit_msg.collection_id)?;
     let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), deposit_msg.collection_id.clone())?;
     
-    let staker_info = STAKING_INFO.may_load(deps.storage, &receive_msg.sender.clone().as_bytes())?;
+    let staker_info = STAKING_INFO.may_load(deps.storage, (deposit_msg.collection_id.as_bytes(), receive_msg.sender.as_bytes()))?;
+
+    let mut cosmos_msgs: Vec<CosmosMsg> = vec![];
+    let mut claimed_rewards = Uint128::from(0u128);
+    let mut penalty = Uint128::from(0u128);
+    let mut penalty_recipient = String::from("none");
 
     if let Some(staking_info) = staker_info {
         if staking_info.total_staked.gt(&Uint128::from(0u128)) {
-            let pending = staking_info.total_staked * collection_pool_info.acc_per_share - staking_info.reward_debt + staking_info.pending;
+            let mut pending = accumulated_reward(staking_info.total_staked, collection_pool_info.acc_per_share) - staking_info.reward_debt + staking_info.pending;
             if pending.gt(&Uint128::from(0u128)) {
+                if deposit_msg.withdraw_rewards {
+                    // Paid out at the same penalty as a claim while the already staked nfts are within their penalty period
+                    if let Some(penalty_rate) = early_withdraw_penalty_rate(&env, &collection_pool_info, &staking_info.staked_tokens) {
+                        penalty = pending * penalty_rate;
+                        pending -= penalty;
+                    }
+                    let staker = deps.api.addr_validate(&receive_msg.sender)?;
+                    if pending.gt(&Uint128::from(0u128)) {
+                        cosmos_msgs.push(build_reward_transfer_msg(
+                            deps.as_ref(),
+                            &env,
+                            &mut sent_rewards,
+                            &collection_pool_info.reward_asset,
+                            &staker,
+                            pending,
+                        )?);
+                    }
+                    claimed_rewards = pending;
+                    let (penalty_msg, recipient) = pay_early_withdraw_penalty(deps.as_ref(), &env, &mut sent_rewards, &mut collection_pool_info, penalty, staking_info.total_staked)?;
+                    cosmos_msgs.extend(penalty_msg);
+                    penalty_recipient = recipient;
+                }
+
                 STAKING_INFO.update(
                     deps.storage, 
-                    &receive_msg.sender.clone().as_bytes(), 
+                    (deposit_msg.collection_id.as_bytes(), receive_msg.sender.as_bytes()), 
                     |data| {
                         if let Some(mut info) = data {
                             if deposit_msg.withdraw_rewards {
@@ -243,232 +816,3484 @@ fn try_receive_721(
             pending: Uint128::from(0u128),
             total_earned: Uint128::from(0u128),
             staked_tokens: vec![],
+            set_bonuses: vec![],
+            stream_rewards: vec![],
         };
 
-        STAKING_INFO.save(deps.storage, &receive_msg.sender.clone().as_bytes(), &user_info)?;        
+        STAKING_INFO.save(deps.storage, (deposit_msg.collection_id.as_bytes(), receive_msg.sender.as_bytes()), &user_info)?;
     }
 
-    // Update the total_staked_nft_editions for collection pool
-    collection_pool_info = COLLECTION_POOL_INFO.update(
-        deps.storage, 
-        deposit_msg.collection_id.clone().as_bytes(),
-        |data| {
-            if let Some(mut collection_info) = data {
-                collection_info.total_nfts += Uint128::from(1u128);
-                Ok(collection_info)
-            } else {
-                return Err(StdError::generic_err("Invalid update collection info"));
-            }
-        })?;
+    let weight = TOKEN_WEIGHTS
+        .may_load(deps.storage, (deposit_msg.collection_id.as_bytes(), &info.sender, &receive_msg.token_id))?
+        .unwrap_or(DEFAULT_TOKEN_WEIGHT);
 
-    //4. Update staker's total_staked_nft_editions and reward debt and staked_nft
-    STAKING_INFO.update(
-        deps.storage, 
-        &receive_msg.sender.clone().as_bytes(),
-        |data| {
-            if let Some(mut user_info) = data {
-                user_info.total_staked += Uint128::from(1u128);
-                user_info.reward_debt = user_info.total_staked * collection_pool_info.acc_per_share.clone();
-                let nft = CollectionStakedTokenInfo{
-                    token_id: receive_msg.token_id,
-                    contract_addr: info.sender.clone()
-                };
-                user_info.staked_tokens.push(nft.clone());
-                Ok(user_info)
-            } else {
-                return Err(StdError::generic_err("Invalid update4 collection staker info"));
-            }
-        }
-    )?;
+    //4. Update staker's staked nfts, stake and reward debt
+    let mut staker_info = STAKING_INFO.load(deps.storage, (deposit_msg.collection_id.as_bytes(), receive_msg.sender.as_bytes()))?;
 
-    // let collection_staker_info_response = 
-    Ok(Response::default())
+    settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+    let mut stream_attrs = vec![];
+    if deposit_msg.withdraw_rewards {
+        let staker = deps.api.addr_validate(&receive_msg.sender)?;
+        let (stream_msgs, attrs) = pay_reward_streams(deps.as_ref(), &env, &mut sent_rewards, &collection_pool_info, &mut staker_info, &staker)?;
+        cosmos_msgs.extend(stream_msgs);
+        stream_attrs = attrs;
+    }
+    staker_info.staked_tokens.push(CollectionStakedTokenInfo{
+        token_id: receive_msg.token_id,
+        contract_addr: info.sender.clone(),
+        staked_at: env.block.height,
+        weight,
+    });
+    // Also updates the total stake of the collection pool
+    let set_bonus_attrs = refresh_staker_stake(deps.storage, env.block.height, &mut collection_pool_info, &mut staker_info)?;
+    STAKING_INFO.save(deps.storage, (deposit_msg.collection_id.as_bytes(), receive_msg.sender.as_bytes()), &staker_info)?;
+    COLLECTION_POOL_INFO.save(deps.storage, deposit_msg.collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_messages(cosmos_msgs)
+        .add_messages(refund_msgs)
+        .add_attribute("action", "stake_nft")
+        .add_attribute("collection_id", deposit_msg.collection_id)
+        .add_attribute("staker", receive_msg.sender)
+        .add_attribute("weight", weight)
+        .add_attribute("claimed_rewards", claimed_rewards)
+        .add_attribute("penalty", penalty)
+        .add_attribute("penalty_recipient", penalty_recipient)
+        .add_attributes(stream_attrs)
+        .add_attributes(set_bonus_attrs)
+        .add_attributes(refund_attrs)
+    )
 }
 
-fn try_update_collection_pool_info(
-    deps: DepsMut, 
-    info: MessageInfo, 
-    msg: UpdateCollectionPoolMsg
+fn try_migrate_collection_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    collection_id: String,
+    reward_asset: RewardAssetInfo,
+    nft_721_contract_addrs: Vec<String>,
 ) -> Result<Response, ContractError> {
     check_admin_permission(deps.as_ref(), &info.sender)?;
 
-    COLLECTION_POOL_INFO.update(
-        deps.storage, 
-        msg.collection_id.clone().as_bytes(), 
-        | data | {
-            if let Some(mut collection_pool_info) = data {
-                if let Some(reward_per_block) = msg.reward_per_block.clone() {
-                    if reward_per_block.le(&Uint128::from(0u128)) {
-                        return Err(ContractError::InvalidRewardPerBlock{});
-                    }
-                    collection_pool_info.reward_per_block = reward_per_block;
-                }
+    migrate_legacy_collection_pool(deps, &collection_id, reward_asset, nft_721_contract_addrs)?;
 
-                return Ok(collection_pool_info);
-            } else {
-                Err(ContractError::Std(StdError::generic_err("invalid update empty!")))
-            }
-        })?;
-        
     Ok(Response::new()
-        .add_attribute("action", "update_collection_pool_info")
-        .add_attribute("collection_id", msg.collection_id)
+        .add_attribute("action", "migrate_collection_pool")
+        .add_attribute("collection_id", collection_id)
     )
 }
 
-fn try_create_collection_pool_info(
-    deps: DepsMut, 
-    env: Env, 
-    info: MessageInfo, 
-    msg: CreateCollectionPoolMsg
-) -> Result<Response, ContractError> {
+fn try_migrate_staker_info(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
     check_admin_permission(deps.as_ref(), &info.sender)?;
 
-    if msg.reward_per_block.le(&Uint128::from(0u128)) {
-        return Err(ContractError::InvalidRewardPerBlock {});
-    }
+    let limit = limit.unwrap_or(DEFAULT_MIGRATION_LIMIT) as usize;
+    let migrated_stakers = migrate_legacy_stakers(deps.storage, &env, &collection_id, Some(limit))?;
 
-    let existed_collection_info = COLLECTION_POOL_INFO.may_load(deps.storage, &msg.collection_id.clone().as_bytes())?;
+    Ok(Response::new()
+        .add_attribute("action", "migrate_staker_info")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("migrated_stakers", migrated_stakers.to_string())
+    )
+}
 
-    if existed_collection_info.is_some() {
-        return Err(ContractError::Std(StdError::generic_err(
-            "Collection info already existed",
-        )));
+fn try_fund_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::Treasurer)?;
+
+    let collection_pool_info = COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+    if collection_pool_info.is_none() {
+        return Err(ContractError::InvalidCollection {});
     }
 
-    let mut new_collection_info = CollectionPoolInfo {
-        collection_id: msg.collection_id.clone(),
-        reward_per_block: msg.reward_per_block.clone(),
-        total_nfts: Uint128::from(0u128),
-        acc_per_share: Uint128::from(0u128),
-        last_reward_block: 0u64,
-        expired_block: None
+    let denom = match collection_pool_info.unwrap().reward_asset {
+        RewardAsset::Native { denom } => denom,
+        RewardAsset::Cw20 { .. } => return Err(ContractError::InvalidRewardFunds {}),
     };
 
-    if let Some(expired_after) = msg.expired_after {
-        new_collection_info.expired_block = Some(env.block.height + expired_after);
+    if info.funds.len() != 1 || info.funds[0].denom != denom || info.funds[0].amount.is_zero() {
+        return Err(ContractError::InvalidRewardFunds {});
     }
 
-    COLLECTION_POOL_INFO.save(
-        deps.storage, 
-        msg.collection_id.clone().as_bytes(), 
-        &new_collection_info,
-    )?;
+    fund_collection_pool(deps.storage, env, collection_id, info.funds[0].amount)
+}
+
+fn try_receive_cw20(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    receive_msg: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    let sender = deps.api.addr_validate(&receive_msg.sender)?;
+
+    match from_binary::<Cw20HookMsg>(&receive_msg.msg)? {
+        Cw20HookMsg::FundPool { collection_id } => {
+            check_role_permission(deps.as_ref(), &sender, Role::Treasurer)?;
+
+            let collection_pool_info = COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+            if collection_pool_info.is_none() {
+                return Err(ContractError::InvalidCollection {});
+            }
+
+            // Only the pool's own reward token can fund it
+            match collection_pool_info.unwrap().reward_asset {
+                RewardAsset::Cw20 { contract_addr } if contract_addr == info.sender => {}
+                _ => return Err(ContractError::InvalidRewardFunds {}),
+            }
+
+            if receive_msg.amount.is_zero() {
+                return Err(ContractError::InvalidRewardFunds {});
+            }
+
+            fund_collection_pool(deps.storage, env, collection_id, receive_msg.amount)
+        }
+        Cw20HookMsg::FundRewardStream { collection_id, stream_id } => {
+            check_role_permission(deps.as_ref(), &sender, Role::Treasurer)?;
+
+            let reward_asset = RewardAsset::Cw20 { contract_addr: info.sender };
+            fund_reward_stream(deps.storage, env, collection_id, stream_id, &reward_asset, receive_msg.amount)
+        }
+        Cw20HookMsg::CreateIncentiveStream { collection_id, reward_per_block, duration_blocks } => {
+            let reward_asset = RewardAsset::Cw20 { contract_addr: info.sender };
+            create_incentive_stream(deps, env, sender, collection_id, reward_asset, receive_msg.amount, reward_per_block, duration_blocks)
+        }
+    }
+}
+
+fn fund_collection_pool(
+    storage: &mut dyn Storage,
+    env: Env,
+    collection_id: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    // Settle the accumulator first so the new funds only cover blocks from now on
+    let mut collection_pool_info = update_collection_pool(storage, env, collection_id.clone())?;
+    collection_pool_info.reward_balance += amount;
+    COLLECTION_POOL_INFO.save(storage, collection_id.as_bytes(), &collection_pool_info)?;
 
     Ok(Response::new()
-        .add_attribute("action", "create_collection_pool")
-        .add_attribute("collection_id", msg.collection_id)
-        .add_attribute("reward_per_block", msg.reward_per_block)
+        .add_attribute("action", "fund_pool")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("amount", amount)
+        .add_attribute("reward_balance", collection_pool_info.reward_balance)
     )
+}
+
+fn try_fund_reward_stream(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+    stream_id: u64,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::Treasurer)?;
 
+    if info.funds.len() != 1 {
+        return Err(ContractError::InvalidRewardFunds {});
+    }
 
+    let reward_asset = RewardAsset::Native { denom: info.funds[0].denom.clone() };
+    fund_reward_stream(deps.storage, env, collection_id, stream_id, &reward_asset, info.funds[0].amount)
 }
 
+/// Only the stream's own reward asset can fund it.
+fn fund_reward_stream(
+    storage: &mut dyn Storage,
+    env: Env,
+    collection_id: String,
+    stream_id: u64,
+    reward_asset: &RewardAsset,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    if !COLLECTION_POOL_INFO.has(storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
+    }
 
-pub fn try_update_contract_info(
+    // Settle the accumulator first so the new funds only cover blocks from now on
+    let mut collection_pool_info = update_collection_pool(storage, env, collection_id.clone())?;
+    let stream = collection_pool_info
+        .reward_streams
+        .iter_mut()
+        .find(|stream| stream.stream_id == stream_id)
+        .ok_or(ContractError::RewardStreamNotFound { stream_id })?;
+
+    // Incentive streams are refunded to their funder, so only they fund them
+    if stream.funder.is_some() || stream.reward_asset != *reward_asset || amount.is_zero() {
+        return Err(ContractError::InvalidRewardFunds {});
+    }
+    stream.reward_balance += amount;
+    let reward_balance = stream.reward_balance;
+
+    COLLECTION_POOL_INFO.save(storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "fund_reward_stream")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("stream_id", stream_id.to_string())
+        .add_attribute("amount", amount)
+        .add_attribute("reward_balance", reward_balance)
+    )
+}
+
+fn try_reclaim_unallocated_rewards(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
-    msg: UpdateContractInfoMsg,
-) -> Result<Response, ContractError>  {
-    check_admin_permission(deps.as_ref(), &info.sender)?;
+    collection_id: String,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::Treasurer)?;
+    let recipient = deps.api.addr_validate(&recipient)?;
 
-    CONTRACT_INFO.update (
-        deps.storage,
-        |mut old_info| -> Result<ContractInfo, ContractError> {
-            if let Some(admin) = msg.admin {
-                old_info.admin = Some(admin);
-            }
-            if let Some(whitelist) = msg.nft_721_contract_addr_whitelist {
-                for addr in whitelist.into_iter() {
-                    let existed = old_info
-                        .nft_721_contract_addr_whitelist
-                        .iter()
-                        .find(|a| a.eq(&&addr));
-                    if existed.is_none() {
-                        old_info.nft_721_contract_addr_whitelist.push(addr);
-                    }
-                }
-            }
-            Ok(old_info)
-        }
+    let collection_pool_info = COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+    if collection_pool_info.is_none() {
+        return Err(ContractError::InvalidCollection {});
+    }
+
+    if !collection_pool_info.unwrap().is_expired(&env) {
+        return Err(ContractError::CollectionNotExpired {});
+    }
+
+    // Everything accrued up to now is owed to stakers, only the rest of the budget can be reclaimed
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+    let amount = collection_pool_info.reward_balance;
+
+    if amount.is_zero() {
+        return Err(ContractError::NoUnallocatedRewards {});
+    }
+
+    let reward_msg = build_reward_transfer_msg(
+        deps.as_ref(),
+        &env,
+        &mut vec![],
+        &collection_pool_info.reward_asset,
+        &recipient,
+        amount,
     )?;
 
+    collection_pool_info.reward_balance = Uint128::from(0u128);
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
     Ok(Response::new()
-        .add_attribute("action", "update_info")
+        .add_message(reward_msg)
+        .add_attribute("action", "reclaim_unallocated_rewards")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("recipient", recipient)
+        .add_attribute("amount", amount)
     )
 }
 
+fn try_reclaim_unallocated_stream_rewards(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+    stream_id: u64,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::Treasurer)?;
+    let recipient = deps.api.addr_validate(&recipient)?;
+
+    if !COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
+    }
+
+    // Everything accrued up to now is owed to stakers, only the rest of the budget can be reclaimed
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+    let is_expired = collection_pool_info.is_expired(&env);
+    let stream = collection_pool_info
+        .reward_streams
+        .iter_mut()
+        .find(|stream| stream.stream_id == stream_id)
+        .ok_or(ContractError::RewardStreamNotFound { stream_id })?;
+
+    if stream.funder.is_some() {
+        return Err(ContractError::Unauthorized { sender: info.sender.to_string() });
+    }
+    if !stream.is_ended(&env) && !is_expired {
+        return Err(ContractError::RewardStreamNotEnded {});
+    }
+
+    let amount = stream.reward_balance;
+    if amount.is_zero() {
+        return Err(ContractError::NoUnallocatedRewards {});
+    }
+
+    let reward_msg = build_reward_transfer_msg(
+        deps.as_ref(),
+        &env,
+        &mut vec![],
+        &stream.reward_asset,
+        &recipient,
+        amount,
+    )?;
+
+    stream.reward_balance = Uint128::from(0u128);
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_message(reward_msg)
+        .add_attribute("action", "reclaim_unallocated_stream_rewards")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("stream_id", stream_id.to_string())
+        .add_attribute("recipient", recipient)
+        .add_attribute("amount", amount)
+    )
+}
+
+fn try_create_native_incentive_stream(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collection_id: String,
+    reward_per_block: Uint128,
+    duration_blocks: u64,
+) -> Result<Response, ContractError> {
+    if info.funds.len() != 1 {
+        return Err(ContractError::InvalidRewardFunds {});
+    }
+
+    let reward_asset = RewardAsset::Native { denom: info.funds[0].denom.clone() };
+    let amount = info.funds[0].amount;
+    create_incentive_stream(deps, env, info.sender, collection_id, reward_asset, amount, reward_per_block, duration_blocks)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_incentive_stream(
+    mut deps: DepsMut,
+    env: Env,
+    funder: Addr,
+    collection_id: String,
+    reward_asset: RewardAsset,
+    amount: Uint128,
+    reward_per_block: Uint128,
+    duration_blocks: u64,
+) -> Result<Response, ContractError> {
+    let collection_pool_info = COLLECTION_POOL_INFO.may_load(deps.storage, collection_id.as_bytes())?;
+    if collection_pool_info.is_none() {
+        return Err(ContractError::InvalidCollection {});
+    }
+    check_collection_is_expired(env.clone(), &collection_pool_info.unwrap())?;
+
+    if reward_per_block.is_zero() || duration_blocks == 0 {
+        return Err(ContractError::InvalidRewardStream {});
+    }
+    let end_block = env.block.height.checked_add(duration_blocks).ok_or(ContractError::InvalidRewardStream {})?;
 
-fn check_admin_permission(deps: Deps, address: &Addr) -> Result<(), ContractError> {
     let contract_info = CONTRACT_INFO.load(deps.storage)?;
-    let admin = contract_info.admin.unwrap();
-    if !admin.eq(address) {
-        return Err(ContractError::Unauthorized {
-            sender: address.to_string(),
-        });
-    } else {
-        Ok(())
+    let incentive_asset = contract_info
+        .incentive_assets
+        .iter()
+        .find(|incentive_asset| incentive_asset.reward_asset == reward_asset)
+        .ok_or(ContractError::IncentiveAssetNotAccepted {})?;
+    if amount < incentive_asset.min_deposit || amount.is_zero() {
+        return Err(ContractError::IncentiveDepositTooLow { min_deposit: incentive_asset.min_deposit });
+    }
+
+    // Frees the slots of ended streams that can be removed
+    let mut sent_rewards: SentRewards = vec![];
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps.branch(), &env, &mut sent_rewards, &collection_id)?;
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+
+    // Ended streams count until they are removed, so the stored streams stay bounded
+    if let Some(max_incentive_streams) = contract_info.max_incentive_streams {
+        let incentive_streams = collection_pool_info
+            .reward_streams
+            .iter()
+            .filter(|stream| stream.funder.is_some())
+            .count();
+        if incentive_streams >= max_incentive_streams as usize {
+            return Err(ContractError::MaxIncentiveStreamsReached { max_incentive_streams });
+        }
     }
+
+    let stream_id = collection_pool_info.next_reward_stream_id;
+    collection_pool_info.reward_streams.push(RewardStream {
+        stream_id,
+        reward_asset,
+        reward_per_block,
+        end_block,
+        last_reward_block: env.block.height,
+        acc_per_share: Uint128::from(0u128),
+        reward_balance: amount,
+        total_distributed: Uint128::from(0u128),
+        funder: Some(funder.clone()),
+        unsettled_stake: None,
+    });
+    collection_pool_info.next_reward_stream_id += 1;
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_messages(refund_msgs)
+        .add_attribute("action", "create_incentive_stream")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("stream_id", stream_id.to_string())
+        .add_attribute("funder", funder)
+        .add_attribute("amount", amount)
+        .add_attributes(refund_attrs)
+    )
 }
 
-fn check_collection_is_expired(
+fn try_refund_ended_incentive_streams(
+    deps: DepsMut,
     env: Env,
-    collection_pool_info: &CollectionPoolInfo,
-) -> Result<bool, ContractError> {
-    //let collection_pool_info = COLLECTION_POOL_INFO.load(store, k)
-    match collection_pool_info.expired_block {
-        Some(expired_block) => {
-            if env.block.height >= expired_block {
-                return Err(ContractError::ExpiredCollection {});
+    collection_id: String,
+) -> Result<Response, ContractError> {
+    let (refund_msgs, refund_attrs) = refund_ended_incentive_streams(deps, &env, &mut vec![], &collection_id)?;
+
+    Ok(Response::new()
+        .add_messages(refund_msgs)
+        .add_attribute("action", "refund_ended_incentive_streams")
+        .add_attribute("collection_id", collection_id)
+        .add_attributes(refund_attrs)
+    )
+}
+
+/// Sends the unallocated budget of every ended incentive stream back to its funder.
+/// Runs before the callers load the pool, since it saves the pool itself.
+fn refund_ended_incentive_streams(
+    deps: DepsMut,
+    env: &Env,
+    sent_rewards: &mut SentRewards,
+    collection_id: &str,
+) -> Result<TransfersWithAttributes, ContractError> {
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.to_string())?;
+    let is_expired = collection_pool_info.is_expired(env);
+
+    let mut cosmos_msgs = vec![];
+    let mut attributes = vec![];
+
+    for stream in collection_pool_info.reward_streams.iter_mut() {
+        if !stream.is_ended(env) && !is_expired {
+            continue;
+        }
+        if let Some(funder) = stream.funder.as_ref() {
+            if stream.reward_balance.is_zero() {
+                continue;
             }
-            Ok(true)
+            cosmos_msgs.push(build_reward_transfer_msg(deps.as_ref(), env, sent_rewards, &stream.reward_asset, funder, stream.reward_balance)?);
+            attributes.push(("refunded_stream", format!("{}:{}", stream.stream_id, stream.reward_balance)));
+            stream.reward_balance = Uint128::from(0u128);
         }
-        None => Ok(true),
     }
+
+    // Refunded streams that every stake has settled against have nothing left to pay out
+    let stream_count = collection_pool_info.reward_streams.len();
+    collection_pool_info.reward_streams.retain(|stream| {
+        let removable = stream.funder.is_some()
+            && stream.reward_balance.is_zero()
+            && stream.unsettled_stake == Some(Uint128::zero());
+        if removable {
+            attributes.push(("removed_stream", stream.stream_id.to_string()));
+        }
+        !removable
+    });
+
+    if !cosmos_msgs.is_empty() || collection_pool_info.reward_streams.len() != stream_count {
+        COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+    }
+
+    Ok((cosmos_msgs, attributes))
 }
 
-fn update_collection_pool(
-    storage: &mut dyn Storage,
+fn try_add_reward_stream(
+    deps: DepsMut,
     env: Env,
-    collection_id: String
-) -> StdResult<CollectionPoolInfo> {
-    let collection_pool_info = COLLECTION_POOL_INFO
-        .load(storage, collection_id.clone().as_bytes())
-        .unwrap();
-    
-    if collection_pool_info.last_reward_block > 0 && env.block.height <= collection_pool_info.last_reward_block {
-        return Ok(collection_pool_info);
+    info: MessageInfo,
+    collection_id: String,
+    reward_asset: RewardAssetInfo,
+    reward_per_block: Uint128,
+    end_block: u64,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    if !COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
     }
+    if reward_per_block.is_zero() || end_block <= env.block.height {
+        return Err(ContractError::InvalidRewardStream {});
+    }
+    let reward_asset = validate_reward_asset(deps.as_ref(), reward_asset)?;
 
-    if collection_pool_info.total_nfts.eq(&Uint128::from(0u128)) {
-        let update_collection_pool_info = 
-            COLLECTION_POOL_INFO.update(storage, collection_id.clone().as_bytes(), | data | {
-                if let Some(mut old_info) = data {
-                    old_info.last_reward_block = env.block.height;
-                    return Ok(old_info);
-                } else {
-                    return Err(StdError::generic_err("Invalid update collection info"));
-                }
-            })?;
-            return Ok(update_collection_pool_info);
-    } else {
-        // Update accumulate_per_share and last_block_reward
-        let multiplier = env.block.height - collection_pool_info.last_reward_block;
-        let reward = collection_pool_info.reward_per_block * Uint128::from(multiplier);
+    let mut collection_pool_info = update_collection_pool(deps.storage, env.clone(), collection_id.clone())?;
+    let stream_id = collection_pool_info.next_reward_stream_id;
+
+    collection_pool_info.reward_streams.push(RewardStream {
+        stream_id,
+        reward_asset,
+        reward_per_block,
+        end_block,
+        last_reward_block: env.block.height,
+        acc_per_share: Uint128::from(0u128),
+        reward_balance: Uint128::from(0u128),
+        total_distributed: Uint128::from(0u128),
+        funder: None,
+        unsettled_stake: None,
+    });
+    collection_pool_info.next_reward_stream_id += 1;
+    COLLECTION_POOL_INFO.save(deps.storage, collection_id.as_bytes(), &collection_pool_info)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "add_reward_stream")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("stream_id", stream_id.to_string())
+    )
+}
+
+fn try_add_set_bonus(
+    deps: DepsMut,
+    info: MessageInfo,
+    collection_id: String,
+    set_id: String,
+    requirement: SetRequirementMsg,
+    multiplier: Decimal,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    if !COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
+    }
+    if multiplier < Decimal::one() {
+        return Err(ContractError::InvalidSetBonus {});
+    }
+
+    let requirement = match requirement {
+        SetRequirementMsg::Contracts { contract_addrs } => {
+            if contract_addrs.is_empty() {
+                return Err(ContractError::InvalidSetBonus {});
+            }
+            SetRequirement::Contracts {
+                contract_addrs: contract_addrs
+                    .iter()
+                    .map(|addr| deps.api.addr_validate(addr))
+                    .collect::<StdResult<Vec<Addr>>>()?,
+            }
+        }
+        SetRequirementMsg::TokenGroups { contract_addr, groups } => {
+            if groups.is_empty() || groups.iter().any(|group| group.is_empty()) {
+                return Err(ContractError::InvalidSetBonus {});
+            }
+            SetRequirement::TokenGroups {
+                contract_addr: deps.api.addr_validate(&contract_addr)?,
+                groups,
+            }
+        }
+    };
+
+    SET_BONUSES.save(deps.storage, (collection_id.as_bytes(), &set_id), &SetBonus {
+        set_id: set_id.clone(),
+        requirement,
+        multiplier,
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "add_set_bonus")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("set_id", set_id)
+        .add_attribute("multiplier", multiplier.to_string())
+    )
+}
+
+fn try_remove_set_bonus(
+    deps: DepsMut,
+    info: MessageInfo,
+    collection_id: String,
+    set_id: String,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    SET_BONUSES.remove(deps.storage, (collection_id.as_bytes(), &set_id));
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_set_bonus")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("set_id", set_id)
+    )
+}
 
-        let update_collection_pool_info = 
-            COLLECTION_POOL_INFO.update(storage, collection_id.clone().as_bytes(), | data | {
-                if let Some(mut old_info) = data {
-                    old_info.acc_per_share = old_info.acc_per_share + reward / collection_pool_info.total_nfts;
-                    old_info.last_reward_block = env.block.height;
-                    return Ok(old_info);
-                } else {
-                    return Err(StdError::generic_err("Invalid update collection info"));
-                };
-            })?;
-            Ok(update_collection_pool_info)
+fn try_set_token_weights(
+    deps: DepsMut,
+    info: MessageInfo,
+    collection_id: String,
+    weights: Vec<TokenWeight>,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    if !COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+        return Err(ContractError::InvalidCollection {});
+    }
 
+    for token_weight in weights.iter() {
+        let contract_addr = deps.api.addr_validate(&token_weight.contract_addr)?;
+        let key = (collection_id.as_bytes(), &contract_addr, token_weight.token_id.as_str());
+        if token_weight.weight.is_zero() {
+            TOKEN_WEIGHTS.remove(deps.storage, key);
+        } else {
+            TOKEN_WEIGHTS.save(deps.storage, key, &token_weight.weight)?;
+        }
     }
+
+    Ok(Response::new()
+        .add_attribute("action", "set_token_weights")
+        .add_attribute("collection_id", collection_id)
+        .add_attribute("num_of_weights", weights.len().to_string())
+    )
 }
 
+fn try_update_collection_pool_info(
+    deps: DepsMut, 
+    env: Env,
+    info: MessageInfo, 
+    msg: UpdateCollectionPoolMsg
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    let nft_721_contract_addrs = match msg.nft_721_contract_addrs {
+        Some(addrs) => Some(validate_nft_721_contract_addrs(deps.as_ref(), addrs)?),
+        None => None,
+    };
+    let early_withdraw_penalty = match msg.early_withdraw_penalty {
+        Some(penalty) => Some(validate_early_withdraw_penalty(deps.as_ref(), penalty)?),
+        None => None,
+    };
+
+    // Settle the accumulator at the old rate so the new one only applies from now on
+    update_collection_pool(deps.storage, env.clone(), msg.collection_id.clone())?;
 
+    COLLECTION_POOL_INFO.update(
+        deps.storage, 
+        msg.collection_id.clone().as_bytes(), 
+        | data | {
+            if let Some(mut collection_pool_info) = data {
+                if let Some(reward_per_block) = msg.reward_per_block.clone() {
+                    if reward_per_block.le(&Uint128::from(0u128)) {
+                        return Err(ContractError::InvalidRewardPerBlock{});
+                    }
+                    collection_pool_info.reward_per_block = reward_per_block;
+                }
+                if let Some(reward_per_second) = msg.reward_per_second {
+                    if reward_per_second.is_zero() {
+                        return Err(ContractError::InvalidRewardPerSecond {});
+                    }
+                    collection_pool_info.reward_per_second = reward_per_second;
+                }
+                if let Some(min_lock_blocks) = msg.min_lock_blocks {
+                    collection_pool_info.min_lock_blocks = min_lock_blocks;
+                }
+                if let Some(early_withdraw_penalty) = early_withdraw_penalty {
+                    collection_pool_info.early_withdraw_penalty = early_withdraw_penalty;
+                }
+                if let Some(nft_721_contract_addrs) = nft_721_contract_addrs {
+                    collection_pool_info.nft_721_contract_addrs = nft_721_contract_addrs;
+                }
+                if let Some(reward_schedule) = msg.reward_schedule {
+                    validate_reward_schedule(&env, collection_pool_info.emission_mode, &reward_schedule)?;
+                    collection_pool_info.reward_schedule = reward_schedule;
+                }
+                if let Some(loyalty_tiers) = msg.loyalty_tiers {
+                    validate_loyalty_tiers(&loyalty_tiers)?;
+                    collection_pool_info.loyalty_tiers = loyalty_tiers;
+                }
+
+                return Ok(collection_pool_info);
+            } else {
+                Err(ContractError::Std(StdError::generic_err("invalid update empty!")))
+            }
+        })?;
+        
+    Ok(Response::new()
+        .add_attribute("action", "update_collection_pool_info")
+        .add_attribute("collection_id", msg.collection_id)
+    )
+}
+
+fn try_create_collection_pool_info(
+    deps: DepsMut, 
+    env: Env, 
+    info: MessageInfo, 
+    msg: CreateCollectionPoolMsg
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::PoolManager)?;
+
+    let mut reward_per_second = Uint128::from(0u128);
+    let mut last_reward_time = 0u64;
+
+    match msg.emission_mode {
+        EmissionMode::Block => {
+            if msg.reward_per_block.le(&Uint128::from(0u128)) {
+                return Err(ContractError::InvalidRewardPerBlock {});
+            }
+
+            // Accrual is bounded by expired_block alone, the time bounds would only clamp the unused last_reward_time
+            if msg.start_time.is_some() || msg.end_time.is_some() {
+                return Err(ContractError::InvalidTimeBounds {});
+            }
+        }
+        EmissionMode::Time => {
+            reward_per_second = msg.reward_per_second.unwrap_or_default();
+            if reward_per_second.is_zero() {
+                return Err(ContractError::InvalidRewardPerSecond {});
+            }
+
+            // Accrual is bounded by end_time alone, an expired_block would be ignored
+            if msg.expired_after.is_some() {
+                return Err(ContractError::InvalidExpiredAfter {});
+            }
+
+            // Nothing accrues before the emission starts
+            last_reward_time = msg.start_time.unwrap_or(0).max(env.block.time.seconds());
+            if let Some(end_time) = msg.end_time {
+                if end_time <= last_reward_time {
+                    return Err(ContractError::InvalidEmissionTime {});
+                }
+            }
+        }
+    }
+
+    let reward_schedule = msg.reward_schedule.unwrap_or_default();
+    validate_reward_schedule(&env, msg.emission_mode, &reward_schedule)?;
+
+    let loyalty_tiers = msg.loyalty_tiers.unwrap_or_default();
+    validate_loyalty_tiers(&loyalty_tiers)?;
+
+    let existed_collection_info = COLLECTION_POOL_INFO.may_load(deps.storage, &msg.collection_id.clone().as_bytes())?;
+
+    if existed_collection_info.is_some() || LEGACY_COLLECTION_POOL_INFO.has(deps.storage, msg.collection_id.as_bytes()) {
+        return Err(ContractError::Std(StdError::generic_err(
+            "Collection info already existed",
+        )));
+    }
+
+    let contract_info = CONTRACT_INFO.load(deps.storage)?;
+
+    if let Some(max_pools) = contract_info.max_pools {
+        let num_of_pools = COLLECTION_POOL_INFO
+            .keys(deps.storage, None, None, Order::Ascending)
+            .count();
+        if num_of_pools >= max_pools as usize {
+            return Err(ContractError::MaxPoolsReached { max_pools });
+        }
+    }
+
+    let early_withdraw_penalty = match msg.early_withdraw_penalty {
+        Some(penalty) => validate_early_withdraw_penalty(deps.as_ref(), penalty)?,
+        None => None,
+    };
+
+    let reward_asset = match msg.reward_asset {
+        Some(reward_asset) => validate_reward_asset(deps.as_ref(), reward_asset)?,
+        None => contract_info.default_reward_asset.ok_or(ContractError::MissingRewardAsset {})?,
+    };
+    let nft_721_contract_addrs = validate_nft_721_contract_addrs(deps.as_ref(), msg.nft_721_contract_addrs)?;
+
+    let mut new_collection_info = CollectionPoolInfo {
+        collection_id: msg.collection_id.clone(),
+        nft_721_contract_addrs,
+        reward_per_block: msg.reward_per_block.clone(),
+        reward_schedule,
+        emission_mode: msg.emission_mode,
+        reward_per_second,
+        start_time: msg.start_time,
+        end_time: msg.end_time,
+        last_reward_time,
+        total_nfts: Uint128::from(0u128),
+        acc_per_share: Uint128::from(0u128),
+        last_reward_block: 0u64,
+        expired_block: None,
+        min_lock_blocks: msg.min_lock_blocks.unwrap_or(0u64),
+        early_withdraw_penalty,
+        loyalty_tiers,
+        reward_streams: vec![],
+        next_reward_stream_id: 0u64,
+        reward_asset,
+        reward_balance: Uint128::from(0u128),
+        total_distributed: Uint128::from(0u128),
+    };
+
+    if let Some(expired_after) = msg.expired_after {
+        new_collection_info.expired_block = Some(env.block.height + expired_after);
+    }
+
+    COLLECTION_POOL_INFO.save(
+        deps.storage, 
+        msg.collection_id.clone().as_bytes(), 
+        &new_collection_info,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "create_collection_pool")
+        .add_attribute("collection_id", msg.collection_id)
+        .add_attribute("reward_per_block", msg.reward_per_block)
+        .add_attribute("reward_per_second", reward_per_second)
+    )
+}
+
+
+pub fn try_update_contract_info(
+    deps: DepsMut,
+    info: MessageInfo,
+    msg: UpdateContractInfoMsg,
+) -> Result<Response, ContractError>  {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+
+    let default_reward_asset = match msg.default_reward_asset {
+        Some(reward_asset) => Some(validate_reward_asset(deps.as_ref(), reward_asset)?),
+        None => None,
+    };
+    let fee_collector = match msg.fee_collector.as_ref() {
+        Some(fee_collector) => Some(deps.api.addr_validate(fee_collector)?),
+        None => None,
+    };
+    let incentive_assets = match msg.incentive_assets {
+        Some(incentive_assets) => Some(validate_incentive_assets(deps.as_ref(), incentive_assets)?),
+        None => None,
+    };
+
+    CONTRACT_INFO.update (
+        deps.storage,
+        |mut old_info| -> Result<ContractInfo, ContractError> {
+            if msg.start_height.is_some() {
+                old_info.start_height = msg.start_height;
+            }
+            if msg.start_time.is_some() {
+                old_info.start_time = msg.start_time;
+            }
+            if msg.end_height.is_some() {
+                old_info.end_height = msg.end_height;
+            }
+            if msg.end_time.is_some() {
+                old_info.end_time = msg.end_time;
+            }
+            if default_reward_asset.is_some() {
+                old_info.default_reward_asset = default_reward_asset;
+            }
+            if fee_collector.is_some() {
+                old_info.fee_collector = fee_collector;
+            }
+            if msg.clear_fee_collector {
+                old_info.fee_collector = None;
+            }
+            if msg.max_pools.is_some() {
+                old_info.max_pools = msg.max_pools;
+            }
+            if msg.clear_max_pools {
+                old_info.max_pools = None;
+            }
+            if let Some(incentive_assets) = incentive_assets {
+                old_info.incentive_assets = incentive_assets;
+            }
+            if msg.max_incentive_streams.is_some() {
+                old_info.max_incentive_streams = msg.max_incentive_streams;
+            }
+            if msg.clear_max_incentive_streams {
+                old_info.max_incentive_streams = None;
+            }
+            Ok(old_info)
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_info")
+    )
+}
+
+pub fn try_propose_new_admin(
+    deps: DepsMut,
+    info: MessageInfo,
+    new_admin: String,
+) -> Result<Response, ContractError> {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+    let new_admin = deps.api.addr_validate(&new_admin)?;
+
+    CONTRACT_INFO.update(
+        deps.storage,
+        |mut old_info| -> Result<ContractInfo, ContractError> {
+            old_info.pending_admin = Some(new_admin.clone());
+            Ok(old_info)
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "propose_new_admin")
+        .add_attribute("pending_admin", new_admin)
+    )
+}
+
+pub fn try_accept_admin(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    CONTRACT_INFO.update(
+        deps.storage,
+        |mut old_info| -> Result<ContractInfo, ContractError> {
+            match old_info.pending_admin {
+                Some(ref pending_admin) if pending_admin.eq(&info.sender) => {
+                    old_info.admin = old_info.pending_admin.take();
+                    Ok(old_info)
+                }
+                Some(..) => Err(ContractError::Unauthorized { sender: info.sender.to_string() }),
+                None => Err(ContractError::NoAdminProposal {}),
+            }
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_admin")
+        .add_attribute("admin", info.sender)
+    )
+}
+
+pub fn try_cancel_admin_proposal(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+
+    CONTRACT_INFO.update(
+        deps.storage,
+        |mut old_info| -> Result<ContractInfo, ContractError> {
+            if old_info.pending_admin.is_none() {
+                return Err(ContractError::NoAdminProposal {});
+            }
+            old_info.pending_admin = None;
+            Ok(old_info)
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "cancel_admin_proposal")
+    )
+}
+
+pub fn try_renounce_admin(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+
+    CONTRACT_INFO.update(
+        deps.storage,
+        |mut old_info| -> Result<ContractInfo, ContractError> {
+            old_info.admin = None;
+            old_info.pending_admin = None;
+            Ok(old_info)
+        }
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "renounce_admin")
+    )
+}
+
+pub fn try_grant_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    role: Role,
+    address: String,
+) -> Result<Response, ContractError> {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+    let address = deps.api.addr_validate(&address)?;
+
+    ROLES.save(deps.storage, (role.as_str(), &address), &())?;
+
+    Ok(Response::new()
+        .add_attribute("action", "grant_role")
+        .add_attribute("role", role.as_str())
+        .add_attribute("address", address)
+    )
+}
+
+pub fn try_revoke_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    role: Role,
+    address: String,
+) -> Result<Response, ContractError> {
+    check_admin_permission(deps.as_ref(), &info.sender)?;
+    let address = deps.api.addr_validate(&address)?;
+
+    ROLES.remove(deps.storage, (role.as_str(), &address));
+
+    Ok(Response::new()
+        .add_attribute("action", "revoke_role")
+        .add_attribute("role", role.as_str())
+        .add_attribute("address", address)
+    )
+}
+
+fn try_set_paused(
+    deps: DepsMut,
+    info: MessageInfo,
+    collection_id: Option<String>,
+    actions: Vec<PausableAction>,
+    paused: bool,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::Pauser)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", if paused { "pause" } else { "unpause" });
+
+    match collection_id {
+        Some(collection_id) => {
+            if !COLLECTION_POOL_INFO.has(deps.storage, collection_id.as_bytes()) {
+                return Err(ContractError::InvalidCollection {});
+            }
+
+            let mut pause_info = COLLECTION_PAUSE_INFO.may_load(deps.storage, collection_id.as_bytes())?.unwrap_or_default();
+            for action in actions.iter() {
+                pause_info.set_paused(*action, paused);
+            }
+            COLLECTION_PAUSE_INFO.save(deps.storage, collection_id.as_bytes(), &pause_info)?;
+            response = response.add_attribute("collection_id", collection_id);
+        }
+        None => {
+            let mut pause_info = CONTRACT_PAUSE_INFO.may_load(deps.storage)?.unwrap_or_default();
+            for action in actions.iter() {
+                pause_info.set_paused(*action, paused);
+            }
+            CONTRACT_PAUSE_INFO.save(deps.storage, &pause_info)?;
+        }
+    }
+
+    for action in actions.iter() {
+        response = response.add_attribute("paused_action", action.as_str());
+    }
+    Ok(response)
+}
+
+fn try_add_to_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    contract_addrs: Vec<String>,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::WhitelistManager)?;
+
+    let mut response = Response::new().add_attribute("action", "add_to_whitelist");
+    for addr in contract_addrs.iter() {
+        let addr = deps.api.addr_validate(addr)?;
+        NFT_721_CONTRACT_WHITELIST.save(deps.storage, &addr, &())?;
+        response = response.add_attribute("contract_addr", addr);
+    }
+
+    Ok(response)
+}
+
+fn try_remove_from_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    contract_addrs: Vec<String>,
+) -> Result<Response, ContractError> {
+    check_role_permission(deps.as_ref(), &info.sender, Role::WhitelistManager)?;
+
+    // Nfts already staked from a removed contract keep their stored contract_addr and can still be withdrawn
+    let mut response = Response::new().add_attribute("action", "remove_from_whitelist");
+    for addr in contract_addrs.iter() {
+        let addr = deps.api.addr_validate(addr)?;
+        NFT_721_CONTRACT_WHITELIST.remove(deps.storage, &addr);
+        response = response.add_attribute("contract_addr", addr);
+    }
+
+    Ok(response)
+}
+
+
+fn check_admin_permission(deps: Deps, address: &Addr) -> Result<(), ContractError> {
+    let contract_info = CONTRACT_INFO.load(deps.storage)?;
+    match contract_info.admin {
+        Some(admin) if admin.eq(address) => Ok(()),
+        _ => Err(ContractError::Unauthorized {
+            sender: address.to_string(),
+        }),
+    }
+}
+
+/// The admin implicitly holds every role.
+fn check_role_permission(deps: Deps, address: &Addr, role: Role) -> Result<(), ContractError> {
+    if ROLES.has(deps.storage, (role.as_str(), address)) {
+        return Ok(());
+    }
+    check_admin_permission(deps, address)
+}
+
+fn check_not_paused(
+    storage: &dyn Storage,
+    collection_id: &str,
+    action: PausableAction,
+) -> Result<(), ContractError> {
+    let contract_pause_info = CONTRACT_PAUSE_INFO.may_load(storage)?.unwrap_or_default();
+    let collection_pause_info = COLLECTION_PAUSE_INFO.may_load(storage, collection_id.as_bytes())?.unwrap_or_default();
+
+    if contract_pause_info.is_paused(action) || collection_pause_info.is_paused(action) {
+        return Err(ContractError::Paused { action: action.as_str().to_string() });
+    }
+    Ok(())
+}
+
+fn check_nfts_unlocked(
+    env: &Env,
+    nfts: &[CollectionStakedTokenInfo],
+    min_lock_blocks: u64,
+) -> Result<(), ContractError> {
+    let locked_tokens = nfts
+        .iter()
+        .map(|nft| (nft, nft.staked_at.saturating_add(min_lock_blocks)))
+        .filter(|(_, unlock_block)| env.block.height < *unlock_block)
+        .map(|(nft, unlock_block)| format!("{} unlocks at block {}", nft.token_id, unlock_block))
+        .collect::<Vec<String>>();
+
+    if !locked_tokens.is_empty() {
+        return Err(ContractError::NftsLocked { locked_tokens: locked_tokens.join(", ") });
+    }
+    Ok(())
+}
+
+/// Rate of the pool's early withdraw penalty while any of `nfts` is still within its penalty period.
+fn early_withdraw_penalty_rate(
+    env: &Env,
+    collection_pool_info: &CollectionPoolInfo,
+    nfts: &[CollectionStakedTokenInfo],
+) -> Option<Decimal> {
+    collection_pool_info
+        .early_withdraw_penalty
+        .as_ref()
+        .filter(|penalty| nfts.iter().any(|nft| env.block.height < nft.staked_at.saturating_add(penalty.lock_blocks)))
+        .map(|penalty| penalty.penalty_rate)
+}
+
+/// Sends `penalty` to the pool's treasury, or the contract's fee collector when the pool has none.
+/// Without either it is split among the stake other than the staker's `own_stake`, and whatever can't be split goes
+/// back to the reward budget. Returns the transfer, if any, and the recipient.
+fn pay_early_withdraw_penalty(
+    deps: Deps,
+    env: &Env,
+    sent_rewards: &mut SentRewards,
+    collection_pool_info: &mut CollectionPoolInfo,
+    penalty: Uint128,
+    own_stake: Uint128,
+) -> Result<(Option<CosmosMsg>, String), ContractError> {
+    if penalty.is_zero() {
+        return Ok((None, String::from("none")));
+    }
+
+    let treasury = match collection_pool_info.early_withdraw_penalty.as_ref().and_then(|p| p.treasury.clone()) {
+        Some(treasury) => Some(treasury),
+        None => CONTRACT_INFO.load(deps.storage)?.fee_collector,
+    };
+    match treasury {
+        Some(treasury) => {
+            let msg = build_reward_transfer_msg(
+                deps,
+                env,
+                sent_rewards,
+                &collection_pool_info.reward_asset,
+                &treasury,
+                penalty,
+            )?;
+            Ok((Some(msg), treasury.to_string()))
+        }
+        None => {
+            // The staker's own nfts don't earn from it, their reward debt is taken afterwards
+            let other_stake = collection_pool_info.total_nfts - own_stake;
+            let mut redistributed = Uint128::from(0u128);
+            if !other_stake.is_zero() {
+                let penalty_per_share = penalty.multiply_ratio(ACC_PER_SHARE_PRECISION, other_stake);
+                collection_pool_info.acc_per_share += penalty_per_share;
+                redistributed = distributed_reward(other_stake, penalty_per_share);
+            }
+            collection_pool_info.reward_balance += penalty - redistributed;
+            collection_pool_info.total_distributed -= penalty - redistributed;
+            Ok((None, String::from("stakers")))
+        }
+    }
+}
+
+fn check_collection_is_expired(
+    env: Env,
+    collection_pool_info: &CollectionPoolInfo,
+) -> Result<bool, ContractError> {
+    if collection_pool_info.is_expired(&env) {
+        return Err(ContractError::ExpiredCollection {});
+    }
+    Ok(true)
+}
+
+fn validate_reward_schedule(
+    env: &Env,
+    emission_mode: EmissionMode,
+    reward_schedule: &[RewardScheduleEntry],
+) -> Result<(), ContractError> {
+    if reward_schedule.is_empty() {
+        return Ok(());
+    }
+
+    if emission_mode != EmissionMode::Block {
+        return Err(ContractError::InvalidRewardSchedule {});
+    }
+
+    let mut last_start_block = env.block.height;
+    for entry in reward_schedule.iter() {
+        if entry.start_block <= last_start_block || entry.reward_per_block.is_zero() {
+            return Err(ContractError::InvalidRewardSchedule {});
+        }
+        last_start_block = entry.start_block;
+    }
+    Ok(())
+}
+
+fn validate_loyalty_tiers(loyalty_tiers: &[LoyaltyTier]) -> Result<(), ContractError> {
+    if loyalty_tiers.iter().any(|tier| tier.multiplier < Decimal::one()) {
+        return Err(ContractError::InvalidLoyaltyTiers {});
+    }
+    if loyalty_tiers.windows(2).any(|tiers| tiers[0].min_staked_blocks >= tiers[1].min_staked_blocks) {
+        return Err(ContractError::InvalidLoyaltyTiers {});
+    }
+    Ok(())
+}
+
+fn validate_nft_721_contract_addrs(deps: Deps, addrs: Vec<String>) -> Result<Vec<Addr>, ContractError> {
+    if addrs.is_empty() {
+        return Err(ContractError::Std(StdError::generic_err(
+            "Collection pool must accept at least one nft contract",
+        )));
+    }
+
+    let mut validated_addrs: Vec<Addr> = vec![];
+    for addr in addrs.iter() {
+        let addr = deps.api.addr_validate(addr)?;
+        if !validated_addrs.contains(&addr) {
+            validated_addrs.push(addr);
+        }
+    }
+    Ok(validated_addrs)
+}
+
+/// A zero penalty rate means no penalty at all.
+fn validate_early_withdraw_penalty(
+    deps: Deps,
+    penalty: EarlyWithdrawPenaltyMsg,
+) -> Result<Option<EarlyWithdrawPenalty>, ContractError> {
+    if penalty.penalty_rate > Decimal::one() {
+        return Err(ContractError::InvalidPenaltyRate {});
+    }
+    if penalty.penalty_rate.is_zero() {
+        return Ok(None);
+    }
+
+    let treasury = match penalty.treasury.as_ref() {
+        Some(treasury) => Some(deps.api.addr_validate(treasury)?),
+        None => None,
+    };
+
+    Ok(Some(EarlyWithdrawPenalty {
+        lock_blocks: penalty.lock_blocks,
+        penalty_rate: penalty.penalty_rate,
+        treasury,
+    }))
+}
+
+fn validate_incentive_assets(deps: Deps, incentive_assets: Vec<IncentiveAssetInfo>) -> StdResult<Vec<IncentiveAsset>> {
+    incentive_assets
+        .into_iter()
+        .map(|incentive_asset| Ok(IncentiveAsset {
+            reward_asset: validate_reward_asset(deps, incentive_asset.reward_asset)?,
+            min_deposit: incentive_asset.min_deposit,
+        }))
+        .collect()
+}
+
+fn validate_reward_asset(deps: Deps, reward_asset: RewardAssetInfo) -> StdResult<RewardAsset> {
+    match reward_asset {
+        RewardAssetInfo::Native { denom } => Ok(RewardAsset::Native { denom }),
+        RewardAssetInfo::Cw20 { contract_addr } => Ok(RewardAsset::Cw20 {
+            contract_addr: deps.api.addr_validate(&contract_addr)?,
+        }),
+    }
+}
+
+/// Builds a reward transfer after checking the contract holds `amount` on top of what `sent_rewards` already committed.
+fn build_reward_transfer_msg(
+    deps: Deps,
+    env: &Env,
+    sent_rewards: &mut SentRewards,
+    reward_asset: &RewardAsset,
+    recipient: &Addr,
+    amount: Uint128,
+) -> Result<CosmosMsg, ContractError> {
+    // Earlier transfers of the same asset in this message draw from the same balance
+    let needed = sent_rewards
+        .iter()
+        .filter(|(asset, _)| asset == reward_asset)
+        .fold(amount, |needed, (_, sent)| needed + *sent);
+
+    let msg = match reward_asset {
+        RewardAsset::Native { denom } => {
+            let balance = deps.querier.query_balance(env.contract.address.clone(), denom.clone())?;
+            if balance.amount.lt(&needed) {
+                return Err(ContractError::InsufficientRewardBalance {
+                    needed,
+                    available: balance.amount,
+                });
+            }
+
+            BankMsg::Send {
+                to_address: recipient.to_string(),
+                amount: coins(amount.u128(), denom.clone()),
+            }.into()
+        }
+        RewardAsset::Cw20 { contract_addr } => {
+            let balance: BalanceResponse = deps.querier.query_wasm_smart(
+                contract_addr.clone(),
+                &Cw20QueryMsg::Balance { address: env.contract.address.to_string() },
+            )?;
+            if balance.balance.lt(&needed) {
+                return Err(ContractError::InsufficientRewardBalance {
+                    needed,
+                    available: balance.balance,
+                });
+            }
+
+            WasmMsg::Execute {
+                contract_addr: contract_addr.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }.into()
+        }
+    };
+
+    sent_rewards.push((reward_asset.clone(), amount));
+    Ok(msg)
+}
+
+fn update_collection_pool(
+    storage: &mut dyn Storage,
+    env: Env,
+    collection_id: String
+) -> StdResult<CollectionPoolInfo> {
+    let collection_pool_info = COLLECTION_POOL_INFO.load(storage, collection_id.as_bytes())?;
+    let updated_collection_pool_info = simulate_collection_pool(&env, collection_pool_info.clone())?;
+
+    if updated_collection_pool_info != collection_pool_info {
+        COLLECTION_POOL_INFO.save(storage, collection_id.as_bytes(), &updated_collection_pool_info)?;
+    }
+
+    Ok(updated_collection_pool_info)
+}
+
+/// Accrues rewards since the last update without touching storage, failing instead of panicking on overflow.
+/// Block based pools stop accruing at `expired_block`, time based pools at `end_time` since they have no `expired_block`.
+fn simulate_collection_pool(
+    env: &Env,
+    mut collection_pool_info: CollectionPoolInfo,
+) -> StdResult<CollectionPoolInfo> {
+    accrue_reward_streams(env, &mut collection_pool_info)?;
+
+    let current_block = match collection_pool_info.expired_block {
+        Some(expired_block) => env.block.height.min(expired_block),
+        None => env.block.height,
+    };
+    let current_time = match collection_pool_info.end_time {
+        Some(end_time) => env.block.time.seconds().min(end_time),
+        None => env.block.time.seconds(),
+    };
+
+    match collection_pool_info.emission_mode {
+        EmissionMode::Block => {
+            if collection_pool_info.last_reward_block > 0 && current_block <= collection_pool_info.last_reward_block {
+                return Ok(collection_pool_info);
+            }
+        }
+        EmissionMode::Time => {
+            if current_time <= collection_pool_info.last_reward_time {
+                return Ok(collection_pool_info);
+            }
+        }
+    }
+
+    if collection_pool_info.total_nfts.eq(&Uint128::from(0u128)) {
+        collection_pool_info.last_reward_block = current_block;
+        collection_pool_info.last_reward_time = current_time;
+        apply_reward_schedule(&mut collection_pool_info);
+        return Ok(collection_pool_info);
+    }
+
+    let emission = match collection_pool_info.emission_mode {
+        EmissionMode::Block => block_emission(&collection_pool_info, current_block)?,
+        EmissionMode::Time => collection_pool_info.reward_per_second
+            .checked_mul(Uint128::from(current_time - collection_pool_info.last_reward_time))?,
+    };
+
+    // Update accumulate_per_share and last_block_reward, never accruing more than the funded budget
+    let reward = emission.min(collection_pool_info.reward_balance);
+
+    let reward_per_share = reward_per_share(reward, collection_pool_info.total_nfts)?;
+    let distributed = distributed_reward(collection_pool_info.total_nfts, reward_per_share);
+
+    collection_pool_info.acc_per_share = collection_pool_info.acc_per_share.checked_add(reward_per_share)?;
+    collection_pool_info.reward_balance = collection_pool_info.reward_balance.checked_sub(distributed)?;
+    collection_pool_info.total_distributed = collection_pool_info.total_distributed.checked_add(distributed)?;
+    collection_pool_info.last_reward_block = current_block;
+    collection_pool_info.last_reward_time = current_time;
+    apply_reward_schedule(&mut collection_pool_info);
+    Ok(collection_pool_info)
+}
+
+/// Accrues every reward stream up to its `end_block`, never beyond its funded budget.
+fn accrue_reward_streams(env: &Env, collection_pool_info: &mut CollectionPoolInfo) -> StdResult<()> {
+    let total_nfts = collection_pool_info.total_nfts;
+    let expired_block = collection_pool_info.expired_block;
+
+    for stream in collection_pool_info.reward_streams.iter_mut() {
+        let mut end_block = stream.end_block;
+        if let Some(expired_block) = expired_block {
+            end_block = end_block.min(expired_block);
+        }
+        let current_block = env.block.height.min(end_block);
+
+        if current_block > stream.last_reward_block {
+            if !total_nfts.is_zero() {
+                let emission = stream.reward_per_block.checked_mul(Uint128::from(current_block - stream.last_reward_block))?;
+                let reward = emission.min(stream.reward_balance);
+                let reward_per_share = reward_per_share(reward, total_nfts)?;
+                let distributed = distributed_reward(total_nfts, reward_per_share);
+
+                stream.acc_per_share = stream.acc_per_share.checked_add(reward_per_share)?;
+                stream.reward_balance = stream.reward_balance.checked_sub(distributed)?;
+                stream.total_distributed = stream.total_distributed.checked_add(distributed)?;
+            }
+            stream.last_reward_block = current_block;
+        }
+
+        // Stakes only change after an update, so the pool's stake is still the one the stream ended with
+        if current_block == end_block && stream.unsettled_stake.is_none() {
+            stream.unsettled_stake = Some(total_nfts);
+        }
+    }
+    Ok(())
+}
+
+/// Rewards emitted from `last_reward_block` up to `current_block`, switching rates at each schedule entry.
+fn block_emission(collection_pool_info: &CollectionPoolInfo, current_block: u64) -> StdResult<Uint128> {
+    let mut emission = Uint128::from(0u128);
+    let mut from_block = collection_pool_info.last_reward_block;
+    let mut reward_per_block = collection_pool_info.reward_per_block;
+
+    for entry in collection_pool_info.reward_schedule.iter() {
+        if entry.start_block >= current_block {
+            break;
+        }
+        if entry.start_block > from_block {
+            emission = emission.checked_add(reward_per_block.checked_mul(Uint128::from(entry.start_block - from_block))?)?;
+            from_block = entry.start_block;
+        }
+        reward_per_block = entry.reward_per_block;
+    }
+
+    Ok(emission.checked_add(reward_per_block.checked_mul(Uint128::from(current_block - from_block))?)?)
+}
+
+/// Folds the schedule entries that already started into `reward_per_block`.
+fn apply_reward_schedule(collection_pool_info: &mut CollectionPoolInfo) {
+    let last_reward_block = collection_pool_info.last_reward_block;
+    let mut reward_per_block = collection_pool_info.reward_per_block;
+
+    collection_pool_info.reward_schedule.retain(|entry| {
+        if entry.start_block <= last_reward_block {
+            reward_per_block = entry.reward_per_block;
+            return false;
+        }
+        true
+    });
+    collection_pool_info.reward_per_block = reward_per_block;
+}
+
+/// A staker's stake is the summed weight of their nfts boosted by their loyalty tier,
+/// multiplied by the best set bonus they complete.
+/// Returns the stake along with the ids of every completed set.
+fn compute_staker_stake(
+    storage: &dyn Storage,
+    collection_pool_info: &CollectionPoolInfo,
+    current_block: u64,
+    staked_tokens: &[CollectionStakedTokenInfo],
+) -> StdResult<(Uint128, Vec<String>)> {
+    let total_stake = staked_tokens
+        .iter()
+        .fold(Uint128::from(0u128), |total, token| {
+            total + token.weight * STAKE_PRECISION * collection_pool_info.loyalty_multiplier(token.staked_at, current_block)
+        });
+
+    let completed_sets = SET_BONUSES
+        .prefix(collection_pool_info.collection_id.as_bytes())
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, set_bonus)| set_bonus))
+        .collect::<StdResult<Vec<SetBonus>>>()?
+        .into_iter()
+        .filter(|set_bonus| set_bonus.is_completed(staked_tokens))
+        .collect::<Vec<SetBonus>>();
+
+    let multiplier = completed_sets
+        .iter()
+        .map(|set_bonus| set_bonus.multiplier)
+        .fold(Decimal::one(), |best, multiplier| best.max(multiplier));
+
+    Ok((
+        total_stake * multiplier,
+        completed_sets.into_iter().map(|set_bonus| set_bonus.set_id).collect(),
+    ))
+}
+
+/// Moves what a staker earned on each reward stream at their current stake into `pending`,
+/// counting the stake as settled on the streams that stopped accruing.
+fn settle_reward_streams(collection_pool_info: &mut CollectionPoolInfo, staker_info: &mut StakerInfo) {
+    let total_staked = staker_info.total_staked;
+
+    for stream in collection_pool_info.reward_streams.iter_mut() {
+        let accumulated = accumulated_reward(total_staked, stream.acc_per_share);
+        let reward = match staker_info.stream_rewards.iter().position(|reward| reward.stream_id == stream.stream_id) {
+            Some(index) => {
+                let reward = &mut staker_info.stream_rewards[index];
+                reward.pending += accumulated - reward.reward_debt;
+                reward.reward_debt = accumulated;
+                reward
+            }
+            // The stream was added after the stake, which has earned everything it accrued since
+            None => {
+                staker_info.stream_rewards.push(StakerStreamReward {
+                    stream_id: stream.stream_id,
+                    pending: accumulated,
+                    reward_debt: accumulated,
+                    ..StakerStreamReward::default()
+                });
+                staker_info.stream_rewards.last_mut().unwrap()
+            }
+        };
+
+        if let Some(unsettled_stake) = stream.unsettled_stake {
+            if reward.reward_asset.is_none() {
+                stream.unsettled_stake = Some(unsettled_stake.saturating_sub(total_staked));
+                reward.reward_asset = Some(stream.reward_asset.clone());
+            }
+        }
+    }
+
+    remove_paid_stream_rewards(collection_pool_info, staker_info);
+}
+
+/// Drops the paid out rewards of streams that were removed from the pool.
+fn remove_paid_stream_rewards(collection_pool_info: &CollectionPoolInfo, staker_info: &mut StakerInfo) {
+    staker_info.stream_rewards.retain(|reward| {
+        !reward.pending.is_zero()
+            || collection_pool_info.reward_streams.iter().any(|stream| stream.stream_id == reward.stream_id)
+    });
+}
+
+/// Resets the reward debt on every reward stream once a staker's stake changed.
+fn reset_reward_stream_debts(collection_pool_info: &CollectionPoolInfo, staker_info: &mut StakerInfo) {
+    for reward in staker_info.stream_rewards.iter_mut() {
+        if let Some(stream) = collection_pool_info.reward_streams.iter().find(|stream| stream.stream_id == reward.stream_id) {
+            reward.reward_debt = accumulated_reward(staker_info.total_staked, stream.acc_per_share);
+        }
+    }
+}
+
+/// Pays out the settled rewards of every reward stream, returning the transfers and their attributes.
+fn pay_reward_streams(
+    deps: Deps,
+    env: &Env,
+    sent_rewards: &mut SentRewards,
+    collection_pool_info: &CollectionPoolInfo,
+    staker_info: &mut StakerInfo,
+    recipient: &Addr,
+) -> Result<TransfersWithAttributes, ContractError> {
+    let mut cosmos_msgs = vec![];
+    let mut attributes = vec![];
+
+    for reward in staker_info.stream_rewards.iter_mut() {
+        if reward.pending.is_zero() {
+            continue;
+        }
+        // Removed streams are paid in the asset kept when the stake settled against them
+        let reward_asset = match collection_pool_info.reward_streams.iter().find(|stream| stream.stream_id == reward.stream_id) {
+            Some(stream) => stream.reward_asset.clone(),
+            None => reward.reward_asset.clone().ok_or(ContractError::RewardStreamNotFound { stream_id: reward.stream_id })?,
+        };
+
+        cosmos_msgs.push(build_reward_transfer_msg(deps, env, sent_rewards, &reward_asset, recipient, reward.pending)?);
+        attributes.push(("claimed_stream_rewards", format!("{}:{}", reward.stream_id, reward.pending)));
+        reward.total_earned += reward.pending;
+        reward.pending = Uint128::from(0u128);
+    }
+    remove_paid_stream_rewards(collection_pool_info, staker_info);
+
+    Ok((cosmos_msgs, attributes))
+}
+
+/// Moves the rewards a staker accrued up to the pool's last update back into the budgets they were taken from,
+/// returning the main reward amount. Best effort: amounts that don't add up are left where they are.
+fn forfeit_staker_rewards(collection_pool_info: &mut CollectionPoolInfo, staker_info: &StakerInfo) -> Uint128 {
+    let mut forfeited_rewards = Uint128::from(0u128);
+    let forfeited = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share)
+        .checked_sub(staker_info.reward_debt)
+        .ok()
+        .and_then(|reward| reward.checked_add(staker_info.pending).ok());
+    if let Some(forfeited) = forfeited {
+        if let Ok(total_distributed) = collection_pool_info.total_distributed.checked_sub(forfeited) {
+            collection_pool_info.total_distributed = total_distributed;
+            collection_pool_info.reward_balance += forfeited;
+            forfeited_rewards = forfeited;
+        }
+    }
+
+    for stream in collection_pool_info.reward_streams.iter_mut() {
+        // Streams added after the stake have no entry yet, the stake earned all they accrued
+        let (reward_debt, pending, settled) = staker_info
+            .stream_rewards
+            .iter()
+            .find(|reward| reward.stream_id == stream.stream_id)
+            .map(|reward| (reward.reward_debt, reward.pending, reward.reward_asset.is_some()))
+            .unwrap_or_default();
+        if let Some(unsettled_stake) = stream.unsettled_stake.filter(|_| !settled) {
+            stream.unsettled_stake = Some(unsettled_stake.saturating_sub(staker_info.total_staked));
+        }
+        let forfeited = accumulated_reward(staker_info.total_staked, stream.acc_per_share)
+            .checked_sub(reward_debt)
+            .ok()
+            .and_then(|reward| reward.checked_add(pending).ok());
+        if let Some(forfeited) = forfeited {
+            if let Ok(total_distributed) = stream.total_distributed.checked_sub(forfeited) {
+                stream.total_distributed = total_distributed;
+                stream.reward_balance += forfeited;
+            }
+        }
+    }
+
+    forfeited_rewards
+}
+
+/// Recalculates a staker's stake once their pending rewards are settled, keeping the pool total in line.
+/// Returns the set bonus attributes.
+fn refresh_staker_stake(
+    storage: &dyn Storage,
+    current_block: u64,
+    collection_pool_info: &mut CollectionPoolInfo,
+    staker_info: &mut StakerInfo,
+) -> StdResult<Vec<(&'static str, String)>> {
+    let (stake, set_bonuses) = compute_staker_stake(storage, collection_pool_info, current_block, &staker_info.staked_tokens)?;
+    let attributes = set_bonus_attributes(&staker_info.set_bonuses, &set_bonuses);
+
+    collection_pool_info.total_nfts = collection_pool_info.total_nfts - staker_info.total_staked + stake;
+    staker_info.total_staked = stake;
+    staker_info.reward_debt = accumulated_reward(stake, collection_pool_info.acc_per_share);
+    reset_reward_stream_debts(collection_pool_info, staker_info);
+    staker_info.set_bonuses = set_bonuses;
+    Ok(attributes)
+}
+
+fn set_bonus_attributes(previous: &[String], current: &[String]) -> Vec<(&'static str, String)> {
+    let gained = current
+        .iter()
+        .filter(|set_id| !previous.contains(set_id))
+        .map(|set_id| ("set_bonus_gained", set_id.clone()));
+    let lost = previous
+        .iter()
+        .filter(|set_id| !current.contains(set_id))
+        .map(|set_id| ("set_bonus_lost", set_id.clone()));
+    gained.chain(lost).collect()
+}
+
+/// Rewards earned by `total_staked` shares at `acc_per_share`, scaled back down by `ACC_PER_SHARE_PRECISION`.
+fn accumulated_reward(total_staked: Uint128, acc_per_share: Uint128) -> Uint128 {
+    total_staked.multiply_ratio(acc_per_share, ACC_PER_SHARE_PRECISION)
+}
+
+/// Accrual per share when `reward` is spread over `total_nfts` shares, scaled up by `ACC_PER_SHARE_PRECISION`.
+fn reward_per_share(reward: Uint128, total_nfts: Uint128) -> StdResult<Uint128> {
+    reward
+        .checked_multiply_ratio(ACC_PER_SHARE_PRECISION, total_nfts)
+        .map_err(|err| StdError::generic_err(err.to_string()))
+}
+
+/// Rewards taken from the budget when `reward_per_share` accrues to `total_nfts` shares.
+/// Rounded up, since stakers can together claim slightly more than the rounded down share, which is then dust
+/// that stays in the contract instead of going back to the budget.
+fn distributed_reward(total_nfts: Uint128, reward_per_share: Uint128) -> Uint128 {
+    let distributed = accumulated_reward(total_nfts, reward_per_share);
+    if (total_nfts.full_mul(reward_per_share) % Uint256::from(ACC_PER_SHARE_PRECISION)).is_zero() {
+        distributed
+    } else {
+        distributed + Uint128::from(1u128)
+    }
+}
+
+#[entry_point]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::ContractInfo {} => to_binary(&query_contract_info(deps)?),
+        QueryMsg::PauseInfo { collection_id } => to_binary(&query_pause_info(deps, collection_id)?),
+        QueryMsg::RoleHolders { role, start_after, limit } => to_binary(&query_role_holders(deps, role, start_after, limit)?),
+        QueryMsg::Whitelist { start_after, limit } => to_binary(&query_whitelist(deps, start_after, limit)?),
+        QueryMsg::CollectionPool { collection_id } => to_binary(&query_collection_pool(deps, collection_id)?),
+        QueryMsg::ListCollectionPools { start_after, limit } => to_binary(&query_list_collection_pools(deps, start_after, limit)?),
+        QueryMsg::SetBonuses { collection_id, start_after, limit } => to_binary(&query_set_bonuses(deps, collection_id, start_after, limit)?),
+        QueryMsg::TokenWeight { collection_id, contract_addr, token_id } => to_binary(&query_token_weight(deps, collection_id, contract_addr, token_id)?),
+        QueryMsg::StakerInfo { collection_id, staker } => to_binary(&query_staker_info(deps, collection_id, staker)?),
+        QueryMsg::StakedTokenTiers { collection_id, staker } => to_binary(&query_staked_token_tiers(deps, env, collection_id, staker)?),
+        QueryMsg::PendingReward { collection_id, staker } => to_binary(&query_pending_reward(deps, env, collection_id, staker)?),
+    }
+}
+
+fn query_contract_info(deps: Deps) -> StdResult<ContractInfoResponse> {
+    let contract_info = CONTRACT_INFO.load(deps.storage)?;
+    Ok(ContractInfoResponse { contract_info })
+}
+
+fn query_pause_info(deps: Deps, collection_id: Option<String>) -> StdResult<PauseInfoResponse> {
+    let pause_info = match collection_id.as_ref() {
+        Some(collection_id) => COLLECTION_PAUSE_INFO.may_load(deps.storage, collection_id.as_bytes())?,
+        None => CONTRACT_PAUSE_INFO.may_load(deps.storage)?,
+    };
+
+    Ok(PauseInfoResponse {
+        collection_id,
+        pause_info: pause_info.unwrap_or_default(),
+    })
+}
+
+fn query_role_holders(
+    deps: Deps,
+    role: Role,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<RoleHoldersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT) as usize;
+    let start_after = start_after.map(|addr| deps.api.addr_validate(&addr)).transpose()?;
+    let start = start_after.as_ref().map(Bound::exclusive);
+
+    let holders = ROLES
+        .prefix(role.as_str())
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<Addr>>>()?;
+
+    Ok(RoleHoldersResponse { role, holders })
+}
+
+fn query_whitelist(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<WhitelistResponse> {
+    let limit = limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT) as usize;
+    let start_after = start_after.map(|addr| deps.api.addr_validate(&addr)).transpose()?;
+    let start = start_after.as_ref().map(Bound::exclusive);
+
+    let contract_addrs = NFT_721_CONTRACT_WHITELIST
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<Addr>>>()?;
+
+    Ok(WhitelistResponse { contract_addrs })
+}
+
+fn query_collection_pool(deps: Deps, collection_id: String) -> StdResult<CollectionPoolResponse> {
+    let collection_pool_info = COLLECTION_POOL_INFO.load(deps.storage, collection_id.as_bytes())?;
+    Ok(CollectionPoolResponse { collection_pool_info })
+}
+
+fn query_list_collection_pools(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListCollectionPoolsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT) as usize;
+    let start = start_after.as_ref().map(|id| Bound::exclusive(id.as_bytes()));
+
+    let collection_pools = COLLECTION_POOL_INFO
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, collection_pool_info)| collection_pool_info))
+        .collect::<StdResult<Vec<CollectionPoolInfo>>>()?;
+
+    Ok(ListCollectionPoolsResponse { collection_pools })
+}
+
+fn query_set_bonuses(
+    deps: Deps,
+    collection_id: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<SetBonusesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT) as usize;
+    let start = start_after.as_ref().map(|set_id| Bound::exclusive(set_id.as_str()));
+
+    let set_bonuses = SET_BONUSES
+        .prefix(collection_id.as_bytes())
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, set_bonus)| set_bonus))
+        .collect::<StdResult<Vec<SetBonus>>>()?;
+
+    Ok(SetBonusesResponse {
+        collection_id,
+        set_bonuses,
+    })
+}
+
+fn query_token_weight(
+    deps: Deps,
+    collection_id: String,
+    contract_addr: String,
+    token_id: String,
+) -> StdResult<TokenWeightResponse> {
+    let contract_addr = deps.api.addr_validate(&contract_addr)?;
+    let weight = TOKEN_WEIGHTS
+        .may_load(deps.storage, (collection_id.as_bytes(), &contract_addr, &token_id))?
+        .unwrap_or(DEFAULT_TOKEN_WEIGHT);
+
+    Ok(TokenWeightResponse {
+        collection_id,
+        contract_addr,
+        token_id,
+        weight,
+    })
+}
+
+fn query_staker_info(
+    deps: Deps,
+    collection_id: String,
+    staker: String,
+) -> StdResult<StakerInfoResponse> {
+    let staker = deps.api.addr_validate(&staker)?;
+    let staker_info = STAKING_INFO.load(deps.storage, (collection_id.as_bytes(), staker.as_bytes()))?;
+
+    Ok(StakerInfoResponse {
+        collection_id,
+        staker,
+        staker_info,
+    })
+}
+
+fn query_staked_token_tiers(
+    deps: Deps,
+    env: Env,
+    collection_id: String,
+    staker: String,
+) -> StdResult<StakedTokenTiersResponse> {
+    let staker = deps.api.addr_validate(&staker)?;
+    let collection_pool_info = COLLECTION_POOL_INFO.load(deps.storage, collection_id.as_bytes())?;
+    let staker_info = STAKING_INFO.load(deps.storage, (collection_id.as_bytes(), staker.as_bytes()))?;
+    let (refreshed_stake, _) = compute_staker_stake(deps.storage, &collection_pool_info, env.block.height, &staker_info.staked_tokens)?;
+
+    let tokens = staker_info
+        .staked_tokens
+        .into_iter()
+        .map(|token| {
+            let tier = collection_pool_info.loyalty_tier(token.staked_at, env.block.height);
+            let next_tier = collection_pool_info.loyalty_tiers.get(tier.map(|tier| tier + 1).unwrap_or(0));
+            StakedTokenTier {
+                tier: tier.map(|tier| tier as u32),
+                multiplier: collection_pool_info.loyalty_multiplier(token.staked_at, env.block.height),
+                next_tier_at: next_tier.map(|next_tier| token.staked_at + next_tier.min_staked_blocks),
+                next_tier_multiplier: next_tier.map(|next_tier| next_tier.multiplier),
+                token_id: token.token_id,
+                contract_addr: token.contract_addr,
+                staked_at: token.staked_at,
+            }
+        })
+        .collect();
+
+    Ok(StakedTokenTiersResponse {
+        collection_id,
+        staker,
+        stake: staker_info.total_staked,
+        refreshed_stake,
+        tokens,
+    })
+}
+
+fn query_pending_reward(
+    deps: Deps,
+    env: Env,
+    collection_id: String,
+    staker: String,
+) -> StdResult<PendingRewardResponse> {
+    let staker = deps.api.addr_validate(&staker)?;
+    let mut collection_pool_info = simulate_collection_pool(
+        &env,
+        COLLECTION_POOL_INFO.load(deps.storage, collection_id.as_bytes())?,
+    )?;
+
+    let mut pending = Uint128::from(0u128);
+    let mut stream_pending = vec![];
+
+    if let Some(mut staker_info) = STAKING_INFO.may_load(deps.storage, (collection_id.as_bytes(), staker.as_bytes()))? {
+        pending = accumulated_reward(staker_info.total_staked, collection_pool_info.acc_per_share) - staker_info.reward_debt + staker_info.pending;
+
+        settle_reward_streams(&mut collection_pool_info, &mut staker_info);
+        for reward in staker_info.stream_rewards.into_iter() {
+            let reward_asset = collection_pool_info
+                .reward_streams
+                .iter()
+                .find(|stream| stream.stream_id == reward.stream_id)
+                .map(|stream| stream.reward_asset.clone())
+                .or(reward.reward_asset);
+            if let Some(reward_asset) = reward_asset {
+                stream_pending.push(StreamPendingReward {
+                    stream_id: reward.stream_id,
+                    reward_asset,
+                    pending: reward.pending,
+                });
+            }
+        }
+    }
+
+    Ok(PendingRewardResponse {
+        collection_id,
+        staker,
+        pending,
+        stream_pending,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_dependencies_with_balance, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{Attribute, Coin, OwnedDeps, Timestamp};
+    use cw_storage_plus::Map;
+    use serde::{Deserialize, Serialize};
+
+    use crate::msg::LegacyCollectionPoolMigration;
+    use crate::state::{LegacyCollectionPoolInfo, LegacyContractInfo};
+
+    const ADMIN: &str = "admin";
+    const NFT_CONTRACT: &str = "nft";
+    const COLLECTION_ID: &str = "collection";
+    const REWARD_DENOM: &str = "reward";
+
+    type MockDeps = OwnedDeps<MockStorage, MockApi, MockQuerier>;
+
+    fn env_at(height: u64) -> Env {
+        let mut env = mock_env();
+        env.block.height = height;
+        env
+    }
+
+    fn attribute(attributes: &[Attribute], key: &str) -> String {
+        attributes
+            .iter()
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .unwrap_or_else(|| panic!("missing attribute {}", key))
+    }
+
+    fn pool_msg(reward_per_block: u128, expired_after: Option<u64>) -> CreateCollectionPoolMsg {
+        CreateCollectionPoolMsg {
+            collection_id: COLLECTION_ID.to_string(),
+            nft_721_contract_addrs: vec![NFT_CONTRACT.to_string()],
+            reward_per_block: Uint128::from(reward_per_block),
+            expired_after,
+            min_lock_blocks: None,
+            early_withdraw_penalty: None,
+            reward_asset: Some(RewardAssetInfo::Native { denom: REWARD_DENOM.to_string() }),
+            emission_mode: EmissionMode::Block,
+            reward_per_second: None,
+            start_time: None,
+            end_time: None,
+            reward_schedule: None,
+            loyalty_tiers: None,
+        }
+    }
+
+    /// Instantiates the contract holding `budget` reward tokens and creates a pool funded with all of them at `height`.
+    fn setup_pool(height: u64, budget: u128, msg: CreateCollectionPoolMsg) -> MockDeps {
+        let mut deps = mock_dependencies_with_balance(&coins(budget, REWARD_DENOM));
+        create_pool(&mut deps, height, msg);
+        execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &coins(budget, REWARD_DENOM)), ExecuteMsg::FundPool {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        deps
+    }
+
+    /// Instantiates the contract and creates an unfunded pool at `height`.
+    fn create_pool(deps: &mut MockDeps, height: u64, msg: CreateCollectionPoolMsg) {
+        instantiate_contract(deps, height);
+        execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(msg)).unwrap();
+    }
+
+    fn instantiate_contract(deps: &mut MockDeps, height: u64) {
+        instantiate(deps.as_mut(), env_at(height), mock_info(ADMIN, &[]), InstantiateMsg {
+            start_height: None,
+            start_time: None,
+            end_height: None,
+            end_time: None,
+            admin: None,
+            nft_721_contract_addr_whitelist: vec![NFT_CONTRACT.to_string()],
+            default_reward_asset: None,
+            fee_collector: None,
+            max_pools: None,
+            incentive_assets: None,
+            max_incentive_streams: None,
+        }).unwrap();
+    }
+
+    fn stake(deps: &mut MockDeps, height: u64, staker: &str, token_id: &str) -> Response {
+        stake_from(deps, height, NFT_CONTRACT, staker, token_id)
+    }
+
+    fn stake_from(deps: &mut MockDeps, height: u64, nft_contract: &str, staker: &str, token_id: &str) -> Response {
+        stake_into(deps, height, nft_contract, COLLECTION_ID, staker, token_id)
+    }
+
+    fn stake_into(deps: &mut MockDeps, height: u64, nft_contract: &str, collection_id: &str, staker: &str, token_id: &str) -> Response {
+        execute(deps.as_mut(), env_at(height), mock_info(nft_contract, &[]), ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: staker.to_string(),
+            token_id: token_id.to_string(),
+            msg: to_binary(&DepositeMsg {
+                collection_id: collection_id.to_string(),
+                withdraw_rewards: false,
+                signature_hash: String::new(),
+            }).unwrap(),
+        })).unwrap()
+    }
+
+    fn claim(deps: &mut MockDeps, height: u64, staker: &str) -> Uint128 {
+        let res = execute(deps.as_mut(), env_at(height), mock_info(staker, &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        Uint128::from(attribute(&res.attributes, "claimed_rewards").parse::<u128>().unwrap())
+    }
+
+    fn load_staker(deps: &MockDeps, collection_id: &str, staker: &str) -> StakerInfo {
+        STAKING_INFO.load(&deps.storage, (collection_id.as_bytes(), staker.as_bytes())).unwrap()
+    }
+
+    /// Claims whatever is pending, which may be nothing yet.
+    fn claim_pending(deps: &mut MockDeps, height: u64, staker: &str) -> Uint128 {
+        let res = execute(deps.as_mut(), env_at(height), mock_info(staker, &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        });
+        match res {
+            Ok(res) => Uint128::from(attribute(&res.attributes, "claimed_rewards").parse::<u128>().unwrap()),
+            Err(ContractError::NoPendingRewards {}) => Uint128::from(0u128),
+            Err(err) => panic!("unexpected error {}", err),
+        }
+    }
+
+    fn load_pool(deps: &MockDeps) -> CollectionPoolInfo {
+        COLLECTION_POOL_INFO.load(&deps.storage, COLLECTION_ID.as_bytes()).unwrap()
+    }
+
+    /// Sets the contract's balance of `denom`, keeping its other balances.
+    fn set_contract_balance(deps: &mut MockDeps, denom: &str, amount: u128) {
+        let mut balances = deps.as_ref().querier.query_all_balances(MOCK_CONTRACT_ADDR).unwrap();
+        balances.retain(|balance| balance.denom != denom);
+        balances.push(Coin::new(amount, denom));
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, balances);
+    }
+
+    #[test]
+    fn stakes_in_different_pools_keep_separate_records() {
+        let mut deps = mock_dependencies();
+        create_pool(&mut deps, 100, pool_msg(10, None));
+        let mut other_pool = pool_msg(20, None);
+        other_pool.collection_id = "other".to_string();
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(other_pool)).unwrap();
+
+        stake_into(&mut deps, 100, NFT_CONTRACT, COLLECTION_ID, "alice", "1");
+        stake_into(&mut deps, 100, NFT_CONTRACT, "other", "alice", "2");
+        stake_into(&mut deps, 100, NFT_CONTRACT, "other", "alice", "3");
+
+        let staker_info = load_staker(&deps, COLLECTION_ID, "alice");
+        assert_eq!(staker_info.total_staked, STAKE_PRECISION);
+        assert_eq!(staker_info.staked_tokens.len(), 1);
+        assert_eq!(load_staker(&deps, "other", "alice").total_staked, Uint128::from(2u128) * STAKE_PRECISION);
+
+        // Withdrawing from one pool leaves the other untouched
+        execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: false,
+            withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: "1".to_string() }],
+        }).unwrap();
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").total_staked, Uint128::from(0u128));
+        assert_eq!(load_staker(&deps, "other", "alice").staked_tokens.len(), 2);
+
+        // The other pool's nfts can't be withdrawn through this one
+        let err = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: false,
+            withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: "2".to_string() }],
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::Std(..)));
+    }
+
+    #[test]
+    fn legacy_staker_records_are_moved_into_a_pool() {
+        let mut deps = mock_dependencies();
+        create_pool(&mut deps, 100, pool_msg(10, None));
+        // The legacy nfts were counted by the pool they were staked into
+        let mut collection_pool_info = load_pool(&deps);
+        collection_pool_info.total_nfts = Uint128::from(2u128) * STAKE_PRECISION;
+        COLLECTION_POOL_INFO.save(&mut deps.storage, COLLECTION_ID.as_bytes(), &collection_pool_info).unwrap();
+        let legacy_tokens = vec![CollectionStakedTokenInfo {
+            token_id: "1".to_string(),
+            contract_addr: Addr::unchecked(NFT_CONTRACT),
+            staked_at: 0,
+            weight: DEFAULT_TOKEN_WEIGHT,
+        }];
+        for staker in ["alice", "bob"] {
+            LEGACY_STAKING_INFO.save(&mut deps.storage, staker.as_bytes(), &StakerInfo {
+                total_staked: Uint128::from(1u128),
+                reward_debt: Uint128::from(0u128),
+                pending: Uint128::from(5u128),
+                total_earned: Uint128::from(0u128),
+                staked_tokens: legacy_tokens.clone(),
+                set_bonuses: vec![],
+                stream_rewards: vec![],
+            }).unwrap();
+        }
+
+        let err = execute(deps.as_mut(), env_at(100), mock_info("alice", &[]), ExecuteMsg::MigrateStakerInfo {
+            collection_id: COLLECTION_ID.to_string(),
+            limit: None,
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        // Batches move the records in order and drop them from the legacy map
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::MigrateStakerInfo {
+            collection_id: COLLECTION_ID.to_string(),
+            limit: Some(1),
+        }).unwrap();
+        let staked_tokens = load_staker(&deps, COLLECTION_ID, "alice").staked_tokens;
+        assert_eq!(staked_tokens[0].token_id, legacy_tokens[0].token_id);
+        // Loyalty tiers count from the migration, not from block 0
+        assert_eq!(staked_tokens[0].staked_at, 100);
+        assert!(!LEGACY_STAKING_INFO.has(&deps.storage, b"alice"));
+        assert!(LEGACY_STAKING_INFO.has(&deps.storage, b"bob"));
+
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::MigrateStakerInfo {
+            collection_id: COLLECTION_ID.to_string(),
+            limit: None,
+        }).unwrap();
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "bob").pending, Uint128::from(5u128));
+        assert!(!LEGACY_STAKING_INFO.has(&deps.storage, b"bob"));
+    }
+
+    fn withdraw_with_rewards(deps: &mut MockDeps, height: u64, staker: &str, token_id: &str) -> Result<Response, ContractError> {
+        execute(deps.as_mut(), env_at(height), mock_info(staker, &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: true,
+            withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: token_id.to_string() }],
+        })
+    }
+
+    #[test]
+    fn withdraw_pays_rewards_in_the_pool_reward_asset() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+
+        let res = withdraw_with_rewards(&mut deps, 110, "alice", "1").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "100");
+        assert!(res.messages.iter().any(|msg| msg.msg == CosmosMsg::Bank(BankMsg::Send {
+            to_address: "alice".to_string(),
+            amount: coins(100, REWARD_DENOM),
+        })));
+    }
+
+    #[test]
+    fn withdraw_fails_cleanly_when_the_contract_lacks_the_rewards() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        set_contract_balance(&mut deps, REWARD_DENOM, 30);
+
+        let err = withdraw_with_rewards(&mut deps, 110, "alice", "1").unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::InsufficientRewardBalance { needed, available }
+                if needed == Uint128::from(100u128) && available == Uint128::from(30u128)
+        ));
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").staked_tokens.len(), 1);
+    }
+
+    #[test]
+    fn claim_pays_rewards_and_keeps_the_nfts_staked() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(100u128));
+        let staker_info = load_staker(&deps, COLLECTION_ID, "alice");
+        assert_eq!(staker_info.total_staked, STAKE_PRECISION);
+        assert_eq!(staker_info.total_earned, Uint128::from(100u128));
+
+        let err = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingRewards {}));
+
+        // The staked nft keeps earning from the claim on
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(100u128));
+    }
+
+    #[test]
+    fn queries_report_pools_stakers_and_pending_rewards() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, None));
+        for collection_id in ["other", "another"] {
+            let mut msg = pool_msg(10, None);
+            msg.collection_id = collection_id.to_string();
+            execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(msg)).unwrap();
+        }
+        stake(&mut deps, 100, "alice", "1");
+
+        let pools: ListCollectionPoolsResponse = from_binary(&query(deps.as_ref(), env_at(100), QueryMsg::ListCollectionPools {
+            start_after: Some("another".to_string()),
+            limit: Some(1),
+        }).unwrap()).unwrap();
+        let collection_ids: Vec<String> = pools.collection_pools.into_iter().map(|pool| pool.collection_id).collect();
+        assert_eq!(collection_ids, vec![COLLECTION_ID.to_string()]);
+
+        let staker: StakerInfoResponse = from_binary(&query(deps.as_ref(), env_at(100), QueryMsg::StakerInfo {
+            collection_id: COLLECTION_ID.to_string(),
+            staker: "alice".to_string(),
+        }).unwrap()).unwrap();
+        assert_eq!(staker.staker_info.total_staked, STAKE_PRECISION);
+
+        // Pending rewards include the blocks since the pool was last updated
+        let pending = query_pending_reward(deps.as_ref(), env_at(115), COLLECTION_ID.to_string(), "alice".to_string()).unwrap();
+        assert_eq!(pending.pending, Uint128::from(150u128));
+        assert_eq!(claim(&mut deps, 115, "alice"), pending.pending);
+
+        let pending = query_pending_reward(deps.as_ref(), env_at(115), COLLECTION_ID.to_string(), "bob".to_string()).unwrap();
+        assert_eq!(pending.pending, Uint128::from(0u128));
+    }
+
+    #[test]
+    fn accrual_stops_once_the_budget_runs_out() {
+        let mut deps = setup_pool(100, 100, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+
+        // The budget only covers 10 of the 100 blocks
+        assert_eq!(claim(&mut deps, 200, "alice"), Uint128::from(100u128));
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.reward_balance, Uint128::from(0u128));
+        assert_eq!(collection_pool_info.total_distributed, Uint128::from(100u128));
+
+        let err = execute(deps.as_mut(), env_at(300), mock_info("alice", &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingRewards {}));
+
+        // New funds only cover the blocks from then on
+        set_contract_balance(&mut deps, REWARD_DENOM, 1_000);
+        execute(deps.as_mut(), env_at(300), mock_info(ADMIN, &coins(1_000, REWARD_DENOM)), ExecuteMsg::FundPool {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(claim(&mut deps, 310, "alice"), Uint128::from(100u128));
+    }
+
+    #[test]
+    fn cw20_funding_only_accepts_the_pool_reward_token() {
+        let mut msg = pool_msg(10, None);
+        msg.reward_asset = Some(RewardAssetInfo::Cw20 { contract_addr: "token".to_string() });
+        let mut deps = mock_dependencies();
+        create_pool(&mut deps, 100, msg);
+
+        let fund_msg = || ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: ADMIN.to_string(),
+            amount: Uint128::from(1_000u128),
+            msg: to_binary(&Cw20HookMsg::FundPool { collection_id: COLLECTION_ID.to_string() }).unwrap(),
+        });
+        let err = execute(deps.as_mut(), env_at(100), mock_info("other_token", &[]), fund_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidRewardFunds {}));
+        assert_eq!(load_pool(&deps).reward_balance, Uint128::from(0u128));
+
+        execute(deps.as_mut(), env_at(100), mock_info("token", &[]), fund_msg()).unwrap();
+        assert_eq!(load_pool(&deps).reward_balance, Uint128::from(1_000u128));
+    }
+
+    #[test]
+    fn only_the_unallocated_budget_of_an_expired_pool_can_be_reclaimed() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, Some(50)));
+        stake(&mut deps, 100, "alice", "1");
+        let reclaim_msg = || ExecuteMsg::ReclaimUnallocatedRewards {
+            collection_id: COLLECTION_ID.to_string(),
+            recipient: ADMIN.to_string(),
+        };
+
+        let err = execute(deps.as_mut(), env_at(149), mock_info(ADMIN, &[]), reclaim_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::CollectionNotExpired {}));
+        let err = execute(deps.as_mut(), env_at(150), mock_info("alice", &[]), reclaim_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        // The 50 blocks alice staked for stay claimable
+        let res = execute(deps.as_mut(), env_at(150), mock_info(ADMIN, &[]), reclaim_msg()).unwrap();
+        assert_eq!(attribute(&res.attributes, "amount"), "500");
+        assert_eq!(load_pool(&deps).reward_balance, Uint128::from(0u128));
+
+        let err = execute(deps.as_mut(), env_at(160), mock_info(ADMIN, &[]), reclaim_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::NoUnallocatedRewards {}));
+        assert_eq!(claim(&mut deps, 160, "alice"), Uint128::from(500u128));
+    }
+
+    #[test]
+    fn claim_after_expiry_only_pays_up_to_expired_block() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, Some(50)));
+        stake(&mut deps, 120, "alice", "1");
+
+        // Staked for 30 blocks before the pool expired at block 150
+        assert_eq!(claim(&mut deps, 200, "alice"), Uint128::from(300u128));
+        assert_eq!(load_pool(&deps).last_reward_block, 150);
+
+        // Nothing accrues after expiry, however long the nft stays staked
+        let err = execute(deps.as_mut(), env_at(300), mock_info("alice", &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingRewards {}));
+    }
+
+    #[test]
+    fn withdraw_after_expiry_only_pays_up_to_expired_block() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, Some(50)));
+        stake(&mut deps, 110, "alice", "1");
+        stake(&mut deps, 130, "bob", "2");
+
+        let pending = query_pending_reward(deps.as_ref(), env_at(500), COLLECTION_ID.to_string(), "alice".to_string()).unwrap();
+        // 20 blocks alone, then 20 blocks shared with bob
+        assert_eq!(pending.pending, Uint128::from(300u128));
+
+        let res = withdraw_with_rewards(&mut deps, 500, "alice", "1").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "300");
+        assert_eq!(attribute(&res.attributes, "withdraw_nfts"), "1");
+
+        assert_eq!(claim(&mut deps, 600, "bob"), Uint128::from(100u128));
+        assert_eq!(load_pool(&deps).total_distributed, Uint128::from(400u128));
+    }
+
+    #[test]
+    fn small_reward_over_many_nfts_does_not_truncate() {
+        let mut collection_pool_info = load_pool(&setup_pool(100, 1_000_000, pool_msg(1, None)));
+        collection_pool_info.total_nfts = Uint128::from(1_000_000u128) * STAKE_PRECISION;
+        collection_pool_info.last_reward_block = 100;
+
+        let collection_pool_info = simulate_collection_pool(&env_at(101), collection_pool_info).unwrap();
+        assert_eq!(collection_pool_info.acc_per_share, Uint128::from(1_000_000u128));
+        assert_eq!(collection_pool_info.total_distributed, Uint128::from(1u128));
+        assert_eq!(collection_pool_info.reward_balance, Uint128::from(999_999u128));
+        // The whole pool together earns the block's reward
+        assert_eq!(accumulated_reward(collection_pool_info.total_nfts, collection_pool_info.acc_per_share), Uint128::from(1u128));
+    }
+
+    #[test]
+    fn rounding_dust_stays_bounded() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(1, None));
+        // 999 nfts don't divide a reward of 1 per block evenly
+        let stakers = [("alice", 7u32), ("bob", 13u32), ("carol", 979u32)];
+        let mut token_id = 0;
+        for (staker, num_of_nfts) in stakers.iter() {
+            for _ in 0..*num_of_nfts {
+                token_id += 1;
+                stake(&mut deps, 100, staker, &token_id.to_string());
+            }
+        }
+
+        let mut claimed = [Uint128::from(0u128); 3];
+        for height in 101..=1100u64 {
+            claimed[0] += claim_pending(&mut deps, height, "alice");
+            if height % 10 == 0 {
+                claimed[1] += claim_pending(&mut deps, height, "bob");
+            }
+        }
+        claimed[2] += claim(&mut deps, 1100, "carol");
+
+        // Every staker gets their share of the 1000 emitted rewards, rounded down
+        assert_eq!(claimed, [Uint128::from(7u128), Uint128::from(13u128), Uint128::from(979u128)]);
+
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.total_distributed, Uint128::from(1000u128));
+        assert_eq!(collection_pool_info.reward_balance, Uint128::from(999_000u128));
+        // Stakers never claim more than was taken from the budget, and lose less than one token each
+        let total_claimed = claimed.iter().fold(Uint128::from(0u128), |total, claimed| total + *claimed);
+        assert!(total_claimed <= collection_pool_info.total_distributed);
+        assert!(collection_pool_info.total_distributed - total_claimed < Uint128::from(stakers.len() as u128));
+    }
+
+    fn env_at_time(height: u64, seconds: u64) -> Env {
+        let mut env = env_at(height);
+        env.block.time = Timestamp::from_seconds(seconds);
+        env
+    }
+
+    fn time_pool_msg(reward_per_second: u128, start_time: u64, end_time: u64) -> CreateCollectionPoolMsg {
+        let mut msg = pool_msg(0, None);
+        msg.emission_mode = EmissionMode::Time;
+        msg.reward_per_second = Some(Uint128::from(reward_per_second));
+        msg.start_time = Some(start_time);
+        msg.end_time = Some(end_time);
+        msg
+    }
+
+    fn claim_at(deps: &mut MockDeps, env: Env, staker: &str) -> Result<Response, ContractError> {
+        execute(deps.as_mut(), env, mock_info(staker, &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        })
+    }
+
+    #[test]
+    fn time_based_pool_accrues_between_start_and_end_time() {
+        let now = mock_env().block.time.seconds();
+        let mut deps = setup_pool(100, 1_000_000, time_pool_msg(10, now + 100, now + 200));
+        stake(&mut deps, 100, "alice", "1");
+
+        // Nothing accrues before the emission starts, however many blocks passed
+        let err = claim_at(&mut deps, env_at_time(200, now + 100), "alice").unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingRewards {}));
+
+        let res = claim_at(&mut deps, env_at_time(201, now + 150), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "500");
+
+        // Accrual is capped at end_time
+        let res = claim_at(&mut deps, env_at_time(202, now + 500), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "500");
+        assert_eq!(load_pool(&deps).last_reward_time, now + 200);
+    }
+
+    #[test]
+    fn time_based_pool_expires_at_end_time() {
+        let now = mock_env().block.time.seconds();
+        let mut deps = setup_pool(100, 1_000, time_pool_msg(10, now, now + 50));
+        stake(&mut deps, 100, "alice", "1");
+
+        let reclaim_msg = || ExecuteMsg::ReclaimUnallocatedRewards {
+            collection_id: COLLECTION_ID.to_string(),
+            recipient: ADMIN.to_string(),
+        };
+        let err = execute(deps.as_mut(), env_at_time(101, now + 49), mock_info(ADMIN, &[]), reclaim_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::CollectionNotExpired {}));
+        assert!(!load_pool(&deps).is_expired(&env_at_time(101, now + 49)));
+        assert!(load_pool(&deps).is_expired(&env_at_time(101, now + 50)));
+
+        let err = execute(deps.as_mut(), env_at_time(102, now + 50), mock_info(NFT_CONTRACT, &[]), ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: "bob".to_string(),
+            token_id: "2".to_string(),
+            msg: to_binary(&DepositeMsg {
+                collection_id: COLLECTION_ID.to_string(),
+                withdraw_rewards: false,
+                signature_hash: String::new(),
+            }).unwrap(),
+        })).unwrap_err();
+        assert!(matches!(err, ContractError::ExpiredCollection {}));
+
+        // Only what was not accrued to alice by end_time can be reclaimed
+        let res = execute(deps.as_mut(), env_at_time(103, now + 60), mock_info(ADMIN, &[]), reclaim_msg()).unwrap();
+        assert_eq!(attribute(&res.attributes, "amount"), "500");
+        let res = claim_at(&mut deps, env_at_time(104, now + 70), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "500");
+    }
+
+    #[test]
+    fn block_based_pool_rejects_time_bounds() {
+        let now = mock_env().block.time.seconds();
+        let mut msg = pool_msg(10, None);
+        msg.end_time = Some(now + 100);
+        let mut deps = mock_dependencies();
+        instantiate_contract(&mut deps, 100);
+
+        let err = execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(msg)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidTimeBounds {}));
+    }
+
+    fn schedule_pool_msg(reward_per_block: u128, reward_schedule: &[(u64, u128)]) -> CreateCollectionPoolMsg {
+        let mut msg = pool_msg(reward_per_block, None);
+        msg.reward_schedule = Some(reward_schedule
+            .iter()
+            .map(|(start_block, reward_per_block)| RewardScheduleEntry {
+                start_block: *start_block,
+                reward_per_block: Uint128::from(*reward_per_block),
+            })
+            .collect());
+        msg
+    }
+
+    #[test]
+    fn accrual_crosses_reward_schedule_entries() {
+        let mut deps = setup_pool(100, 1_000_000, schedule_pool_msg(10, &[(110, 20)]));
+        stake(&mut deps, 100, "alice", "1");
+
+        // 10 blocks at 10, then 10 blocks at 20
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(300u128));
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.reward_per_block, Uint128::from(20u128));
+        assert!(collection_pool_info.reward_schedule.is_empty());
+
+        let mut deps = setup_pool(100, 1_000_000, schedule_pool_msg(10, &[(110, 20), (120, 30), (130, 40)]));
+        stake(&mut deps, 100, "alice", "1");
+
+        assert_eq!(claim(&mut deps, 135, "alice"), Uint128::from(100u128 + 200 + 300 + 200));
+        assert_eq!(load_pool(&deps).reward_per_block, Uint128::from(40u128));
+    }
+
+    #[test]
+    fn reward_schedule_entry_at_the_current_block_applies_from_there() {
+        let mut deps = setup_pool(100, 1_000_000, schedule_pool_msg(10, &[(110, 20), (120, 30)]));
+        stake(&mut deps, 100, "alice", "1");
+
+        // Block 110 itself is the first block at the new rate
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(100u128));
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.reward_per_block, Uint128::from(20u128));
+        assert_eq!(collection_pool_info.reward_schedule.len(), 1);
+
+        assert_eq!(claim(&mut deps, 115, "alice"), Uint128::from(100u128));
+        assert_eq!(claim(&mut deps, 125, "alice"), Uint128::from(100u128 + 150));
+    }
+
+    #[test]
+    fn rate_update_settles_at_the_old_rate() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+
+        execute(deps.as_mut(), env_at(110), mock_info(ADMIN, &[]), ExecuteMsg::UpdateCollectionPool(UpdateCollectionPoolMsg {
+            collection_id: COLLECTION_ID.to_string(),
+            nft_721_contract_addrs: None,
+            reward_per_block: Some(Uint128::from(50u128)),
+            reward_per_second: None,
+            min_lock_blocks: None,
+            early_withdraw_penalty: None,
+            reward_schedule: None,
+            loyalty_tiers: None,
+        })).unwrap();
+
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(100u128 + 500));
+    }
+
+    fn withdraw(deps: &mut MockDeps, height: u64, staker: &str, token_id: &str) -> Response {
+        execute(deps.as_mut(), env_at(height), mock_info(staker, &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: true,
+            withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: token_id.to_string() }],
+        }).unwrap()
+    }
+
+    #[test]
+    fn withdraw_picks_the_nft_of_the_given_contract() {
+        let mut msg = pool_msg(10, None);
+        msg.nft_721_contract_addrs.push("nft2".to_string());
+        let mut deps = setup_pool(100, 1_000_000, msg);
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::AddToWhitelist {
+            contract_addrs: vec!["nft2".to_string()],
+        }).unwrap();
+        stake_from(&mut deps, 100, NFT_CONTRACT, "alice", "1");
+        stake_from(&mut deps, 100, "nft2", "alice", "1");
+
+        execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: false,
+            withdraw_nft_ids: vec![NftId { contract_addr: "nft2".to_string(), token_id: "1".to_string() }],
+        }).unwrap();
+
+        let staker_info = load_staker(&deps, COLLECTION_ID, "alice");
+        assert_eq!(staker_info.total_staked, STAKE_PRECISION);
+        assert_eq!(staker_info.staked_tokens.len(), 1);
+        assert_eq!(staker_info.staked_tokens[0].contract_addr, Addr::unchecked(NFT_CONTRACT));
+    }
+
+    #[test]
+    fn mixed_legacy_records_are_split_between_the_pools_of_their_contracts() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        let mut other_pool = pool_msg(10, None);
+        other_pool.collection_id = "other".to_string();
+        other_pool.nft_721_contract_addrs = vec!["nft2".to_string()];
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(other_pool)).unwrap();
+        for collection_id in [COLLECTION_ID, "other"] {
+            COLLECTION_POOL_INFO.update(&mut deps.storage, collection_id.as_bytes(), |data| -> StdResult<CollectionPoolInfo> {
+                let mut collection_pool_info = data.unwrap();
+                collection_pool_info.total_nfts = STAKE_PRECISION;
+                Ok(collection_pool_info)
+            }).unwrap();
+        }
+        LEGACY_STAKING_INFO.save(&mut deps.storage, b"alice", &StakerInfo {
+            total_staked: Uint128::from(2u128),
+            reward_debt: Uint128::from(0u128),
+            pending: Uint128::from(5u128),
+            total_earned: Uint128::from(0u128),
+            staked_tokens: vec![
+                CollectionStakedTokenInfo { token_id: "1".to_string(), contract_addr: Addr::unchecked(NFT_CONTRACT), staked_at: 0, weight: DEFAULT_TOKEN_WEIGHT },
+                CollectionStakedTokenInfo { token_id: "1".to_string(), contract_addr: Addr::unchecked("nft2"), staked_at: 0, weight: DEFAULT_TOKEN_WEIGHT },
+            ],
+            set_bonuses: vec![],
+            stream_rewards: vec![],
+        }).unwrap();
+        let migrate_msg = |collection_id: &str| ExecuteMsg::MigrateStakerInfo {
+            collection_id: collection_id.to_string(),
+            limit: None,
+        };
+
+        // Only the nft of the pool's own contract moves, along with the settled pending rewards
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), migrate_msg(COLLECTION_ID)).unwrap();
+        let staker_info = load_staker(&deps, COLLECTION_ID, "alice");
+        assert_eq!(staker_info.total_staked, STAKE_PRECISION);
+        assert_eq!(staker_info.pending, Uint128::from(5u128));
+        let legacy_info = LEGACY_STAKING_INFO.load(&deps.storage, b"alice").unwrap();
+        assert_eq!(legacy_info.staked_tokens.len(), 1);
+        assert_eq!(legacy_info.pending, Uint128::from(0u128));
+
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), migrate_msg("other")).unwrap();
+        let staker_info = load_staker(&deps, "other", "alice");
+        assert_eq!(staker_info.staked_tokens[0].contract_addr, Addr::unchecked("nft2"));
+        assert_eq!(staker_info.pending, Uint128::from(0u128));
+        assert!(!LEGACY_STAKING_INFO.has(&deps.storage, b"alice"));
+
+        // Both nfts now earn in their own pool
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(105u128));
+    }
+
+    #[test]
+    fn nfts_of_a_removed_contract_can_still_be_withdrawn() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        execute(deps.as_mut(), env_at(105), mock_info(ADMIN, &[]), ExecuteMsg::RemoveFromWhitelist {
+            contract_addrs: vec![NFT_CONTRACT.to_string()],
+        }).unwrap();
+
+        let err = execute(deps.as_mut(), env_at(105), mock_info(NFT_CONTRACT, &[]), ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: "bob".to_string(),
+            token_id: "2".to_string(),
+            msg: to_binary(&DepositeMsg {
+                collection_id: COLLECTION_ID.to_string(),
+                withdraw_rewards: false,
+                signature_hash: String::new(),
+            }).unwrap(),
+        })).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "withdraw_nfts"), "1");
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "100");
+        assert_eq!(res.messages[1].msg, CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: NFT_CONTRACT.to_string(),
+            msg: to_binary(&cw721::Cw721ExecuteMsg::TransferNft {
+                recipient: "alice".to_string(),
+                token_id: "1".to_string(),
+            }).unwrap(),
+            funds: vec![],
+        }));
+    }
+
+    fn admin_call(deps: &mut MockDeps, sender: &str, msg: ExecuteMsg) -> Result<Response, ContractError> {
+        execute(deps.as_mut(), env_at(100), mock_info(sender, &[]), msg)
+    }
+
+    fn load_admin(deps: &MockDeps) -> Option<Addr> {
+        CONTRACT_INFO.load(&deps.storage).unwrap().admin
+    }
+
+    #[test]
+    fn admin_transfer_needs_the_proposed_address_to_accept() {
+        let mut deps = mock_dependencies();
+        instantiate_contract(&mut deps, 100);
+
+        let err = admin_call(&mut deps, "bob", ExecuteMsg::ProposeNewAdmin { new_admin: "bob".to_string() }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+        let err = admin_call(&mut deps, "bob", ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert!(matches!(err, ContractError::NoAdminProposal {}));
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::ProposeNewAdmin { new_admin: "bob".to_string() }).unwrap();
+        let err = admin_call(&mut deps, "carol", ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::CancelAdminProposal {}).unwrap();
+        let err = admin_call(&mut deps, "bob", ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert!(matches!(err, ContractError::NoAdminProposal {}));
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::ProposeNewAdmin { new_admin: "bob".to_string() }).unwrap();
+        assert_eq!(load_admin(&deps), Some(Addr::unchecked(ADMIN)));
+        admin_call(&mut deps, "bob", ExecuteMsg::AcceptAdmin {}).unwrap();
+        assert_eq!(load_admin(&deps), Some(Addr::unchecked("bob")));
+        assert_eq!(CONTRACT_INFO.load(&deps.storage).unwrap().pending_admin, None);
+
+        let err = admin_call(&mut deps, ADMIN, ExecuteMsg::ProposeNewAdmin { new_admin: ADMIN.to_string() }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+    }
+
+    #[test]
+    fn renounced_admin_cannot_be_used_again() {
+        let mut deps = mock_dependencies();
+        instantiate_contract(&mut deps, 100);
+        admin_call(&mut deps, ADMIN, ExecuteMsg::ProposeNewAdmin { new_admin: "bob".to_string() }).unwrap();
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::RenounceAdmin {}).unwrap();
+        assert_eq!(load_admin(&deps), None);
+
+        let err = admin_call(&mut deps, "bob", ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert!(matches!(err, ContractError::NoAdminProposal {}));
+        let err = admin_call(&mut deps, ADMIN, ExecuteMsg::ProposeNewAdmin { new_admin: ADMIN.to_string() }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+        let err = admin_call(&mut deps, ADMIN, ExecuteMsg::AddToWhitelist { contract_addrs: vec!["other_nft".to_string()] }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+    }
+
+    #[test]
+    fn granted_roles_only_unlock_their_own_handlers() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        let update_pool = ExecuteMsg::UpdateCollectionPool(UpdateCollectionPoolMsg {
+            collection_id: COLLECTION_ID.to_string(),
+            nft_721_contract_addrs: None,
+            reward_per_block: Some(Uint128::from(20u128)),
+            reward_per_second: None,
+            min_lock_blocks: None,
+            early_withdraw_penalty: None,
+            reward_schedule: None,
+            loyalty_tiers: None,
+        });
+        let whitelist = ExecuteMsg::AddToWhitelist { contract_addrs: vec!["other_nft".to_string()] };
+
+        let err = admin_call(&mut deps, "manager", update_pool.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+        let err = admin_call(&mut deps, "manager", ExecuteMsg::GrantRole { role: Role::PoolManager, address: "manager".to_string() }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::GrantRole { role: Role::PoolManager, address: "manager".to_string() }).unwrap();
+        admin_call(&mut deps, "manager", update_pool.clone()).unwrap();
+        let err = admin_call(&mut deps, "manager", whitelist).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+        let err = admin_call(&mut deps, "manager", ExecuteMsg::GrantRole { role: Role::WhitelistManager, address: "manager".to_string() }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+
+        let holders: RoleHoldersResponse = from_binary(&query(deps.as_ref(), env_at(100), QueryMsg::RoleHolders {
+            role: Role::PoolManager,
+            start_after: None,
+            limit: None,
+        }).unwrap()).unwrap();
+        assert_eq!(holders.holders, vec![Addr::unchecked("manager")]);
+        let holders = query_role_holders(deps.as_ref(), Role::WhitelistManager, None, None).unwrap();
+        assert!(holders.holders.is_empty());
+
+        admin_call(&mut deps, ADMIN, ExecuteMsg::RevokeRole { role: Role::PoolManager, address: "manager".to_string() }).unwrap();
+        let err = admin_call(&mut deps, "manager", update_pool).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized { .. }));
+        assert!(query_role_holders(deps.as_ref(), Role::PoolManager, None, None).unwrap().holders.is_empty());
+    }
+
+    #[test]
+    fn paused_withdrawals_leave_emergency_withdraw_open() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "alice", "2");
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::Pause {
+            collection_id: None,
+            actions: vec![PausableAction::Withdraw],
+        }).unwrap();
+
+        for withdraw_rewards in [true, false] {
+            let err = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::Withdraw {
+                collection_id: COLLECTION_ID.to_string(),
+                withdraw_rewards,
+                withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: "1".to_string() }],
+            }).unwrap_err();
+            assert!(matches!(err, ContractError::Paused { .. }));
+        }
+
+        // A pool scoped pause only blocks that pool
+        execute(deps.as_mut(), env_at(110), mock_info(ADMIN, &[]), ExecuteMsg::Pause {
+            collection_id: Some(COLLECTION_ID.to_string()),
+            actions: vec![PausableAction::Claim],
+        }).unwrap();
+        let err = claim_at(&mut deps, env_at(110), "alice").unwrap_err();
+        assert!(matches!(err, ContractError::Paused { .. }));
+        execute(deps.as_mut(), env_at(110), mock_info(ADMIN, &[]), ExecuteMsg::Unpause {
+            collection_id: Some(COLLECTION_ID.to_string()),
+            actions: vec![PausableAction::Claim],
+        }).unwrap();
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(100u128));
+
+        let res = execute(deps.as_mut(), env_at(120), mock_info("alice", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(attribute(&res.attributes, "withdraw_nfts"), "2");
+    }
+
+    #[test]
+    fn emergency_withdraw_forfeits_to_the_budget_and_keeps_the_others_accrual() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "bob", "2");
+        assert_eq!(claim(&mut deps, 105, "alice"), Uint128::from(25u128));
+
+        let res = execute(deps.as_mut(), env_at(110), mock_info("bob", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        // Bob's share of blocks 100 to 110 goes back to the budget
+        assert_eq!(attribute(&res.attributes, "forfeited_rewards"), "50");
+
+        // Alice keeps her half of blocks 105 to 110 and earns alone afterwards
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(125u128));
+
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.total_nfts, STAKE_PRECISION);
+        assert_eq!(collection_pool_info.total_distributed, Uint128::from(150u128));
+        assert_eq!(collection_pool_info.reward_balance, Uint128::from(1_000_000u128 - 150));
+    }
+
+    #[test]
+    fn emergency_withdraw_skips_accrual_that_overflows() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        let mut collection_pool_info = load_pool(&deps);
+        collection_pool_info.reward_per_block = Uint128::MAX;
+        COLLECTION_POOL_INFO.save(&mut deps.storage, COLLECTION_ID.as_bytes(), &collection_pool_info).unwrap();
+
+        let err = claim_at(&mut deps, env_at(110), "alice").unwrap_err();
+        assert!(matches!(err, ContractError::Std(StdError::Overflow { .. })));
+
+        let res = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(attribute(&res.attributes, "forfeited_rewards"), "0");
+
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.last_reward_block, 110);
+        assert_eq!(collection_pool_info.total_nfts, Uint128::from(0u128));
+        assert_eq!(collection_pool_info.reward_balance, Uint128::from(1_000_000u128));
+    }
+
+    /// Staker records as saved before staking was scoped per pool
+    #[derive(Serialize, Deserialize)]
+    struct UnversionedStakerInfo {
+        total_staked: Uint128,
+        reward_debt: Uint128,
+        pending: Uint128,
+        total_earned: Uint128,
+        staked_tokens: Vec<UnversionedStakedTokenInfo>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct UnversionedStakedTokenInfo {
+        token_id: String,
+        contract_addr: Addr,
+    }
+
+    const UNVERSIONED_STAKING_INFO: Map<&[u8], UnversionedStakerInfo> = Map::new("staker_info_map");
+
+    fn save_unversioned_staker(deps: &mut MockDeps, staker: &str, total_staked: u128, reward_debt: u128, pending: u128, tokens: &[(&str, &str)]) {
+        UNVERSIONED_STAKING_INFO.save(&mut deps.storage, staker.as_bytes(), &UnversionedStakerInfo {
+            total_staked: Uint128::from(total_staked),
+            reward_debt: Uint128::from(reward_debt),
+            pending: Uint128::from(pending),
+            total_earned: Uint128::from(0u128),
+            staked_tokens: tokens
+                .iter()
+                .map(|(contract_addr, token_id)| UnversionedStakedTokenInfo {
+                    token_id: token_id.to_string(),
+                    contract_addr: Addr::unchecked(*contract_addr),
+                })
+                .collect(),
+        }).unwrap();
+    }
+
+    #[test]
+    fn migrate_rewrites_unversioned_state() {
+        let mut deps = mock_dependencies();
+        LEGACY_CONTRACT_INFO.save(&mut deps.storage, &LegacyContractInfo {
+            source: Addr::unchecked(ADMIN),
+            end_height: None,
+            end_time: None,
+            admin: Some(ADMIN.to_string()),
+            nft_721_contract_addr_whitelist: vec![NFT_CONTRACT.to_string(), "nft2".to_string()],
+        }).unwrap();
+        for (collection_id, total_nfts) in [(COLLECTION_ID, 3u128), ("other", 1u128)] {
+            LEGACY_COLLECTION_POOL_INFO.save(&mut deps.storage, collection_id.as_bytes(), &LegacyCollectionPoolInfo {
+                collection_id: collection_id.to_string(),
+                reward_per_block: Uint128::from(10u128),
+                total_nfts: Uint128::from(total_nfts),
+                acc_per_share: Uint128::from(5u128),
+                last_reward_block: 100,
+                expired_block: None,
+            }).unwrap();
+        }
+        save_unversioned_staker(&mut deps, "alice", 2, 6, 1, &[(NFT_CONTRACT, "1"), (NFT_CONTRACT, "2")]);
+        // Carol's record mixes nfts of both pools
+        save_unversioned_staker(&mut deps, "carol", 2, 10, 3, &[(NFT_CONTRACT, "3"), ("nft2", "1")]);
+
+        let res = migrate(deps.as_mut(), env_at(200), MigrateMsg {
+            legacy_collection_pools: [(COLLECTION_ID, NFT_CONTRACT), ("other", "nft2")]
+                .iter()
+                .map(|(collection_id, nft_contract)| LegacyCollectionPoolMigration {
+                    collection_id: collection_id.to_string(),
+                    reward_asset: RewardAssetInfo::Native { denom: REWARD_DENOM.to_string() },
+                    nft_721_contract_addrs: vec![nft_contract.to_string()],
+                })
+                .collect(),
+            legacy_staker_collection_ids: vec![COLLECTION_ID.to_string(), "other".to_string()],
+        }).unwrap();
+        assert_eq!(attribute(&res.attributes, "from_version"), "unversioned");
+
+        let version = get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.contract, CONTRACT_NAME);
+        assert_eq!(version.version, CONTRACT_VERSION);
+        assert_eq!(CONTRACT_INFO.load(&deps.storage).unwrap().admin, Some(Addr::unchecked(ADMIN)));
+        assert!(NFT_721_CONTRACT_WHITELIST.has(&deps.storage, &Addr::unchecked("nft2")));
+
+        let collection_pool_info = load_pool(&deps);
+        assert_eq!(collection_pool_info.acc_per_share, Uint128::from(5u128) * (ACC_PER_SHARE_PRECISION / STAKE_PRECISION));
+        assert_eq!(collection_pool_info.total_nfts, Uint128::from(3u128) * STAKE_PRECISION);
+        assert_eq!(collection_pool_info.nft_721_contract_addrs, vec![Addr::unchecked(NFT_CONTRACT)]);
+        assert_eq!(LEGACY_COLLECTION_POOL_INFO.keys(&deps.storage, None, None, Order::Ascending).count(), 0);
+
+        // Alice keeps what she earned since her last deposit
+        let alice = load_staker(&deps, COLLECTION_ID, "alice");
+        assert_eq!(alice.total_staked, Uint128::from(2u128) * STAKE_PRECISION);
+        assert_eq!(alice.pending, Uint128::from(5u128));
+        assert_eq!(alice.reward_debt, Uint128::from(10u128));
+        // Loyalty tiers count from the migration, not from block 0
+        assert!(alice.staked_tokens.iter().all(|token| token.staked_at == 200));
+
+        // Carol's nfts are split by contract, only her settled rewards can be carried over
+        let carol = load_staker(&deps, COLLECTION_ID, "carol");
+        assert_eq!(carol.total_staked, STAKE_PRECISION);
+        assert_eq!(carol.pending, Uint128::from(3u128));
+        assert_eq!(carol.staked_tokens[0].token_id, "3");
+        let carol = load_staker(&deps, "other", "carol");
+        assert_eq!(carol.total_staked, STAKE_PRECISION);
+        assert_eq!(carol.pending, Uint128::from(0u128));
+        assert_eq!(carol.staked_tokens[0].contract_addr, Addr::unchecked("nft2"));
+        assert_eq!(COLLECTION_POOL_INFO.load(&deps.storage, b"other").unwrap().total_nfts, STAKE_PRECISION);
+        assert_eq!(LEGACY_STAKING_INFO.keys(&deps.storage, None, None, Order::Ascending).count(), 0);
+    }
+
+    #[test]
+    fn migrate_refuses_legacy_stakes_the_pool_does_not_hold() {
+        let mut deps = mock_dependencies();
+        LEGACY_CONTRACT_INFO.save(&mut deps.storage, &LegacyContractInfo {
+            source: Addr::unchecked(ADMIN),
+            end_height: None,
+            end_time: None,
+            admin: Some(ADMIN.to_string()),
+            nft_721_contract_addr_whitelist: vec![NFT_CONTRACT.to_string()],
+        }).unwrap();
+        LEGACY_COLLECTION_POOL_INFO.save(&mut deps.storage, COLLECTION_ID.as_bytes(), &LegacyCollectionPoolInfo {
+            collection_id: COLLECTION_ID.to_string(),
+            reward_per_block: Uint128::from(10u128),
+            total_nfts: Uint128::from(1u128),
+            acc_per_share: Uint128::from(0u128),
+            last_reward_block: 100,
+            expired_block: None,
+        }).unwrap();
+        save_unversioned_staker(&mut deps, "alice", 2, 0, 0, &[(NFT_CONTRACT, "1"), (NFT_CONTRACT, "2")]);
+
+        let err = migrate(deps.as_mut(), env_at(200), MigrateMsg {
+            legacy_collection_pools: vec![LegacyCollectionPoolMigration {
+                collection_id: COLLECTION_ID.to_string(),
+                reward_asset: RewardAssetInfo::Native { denom: REWARD_DENOM.to_string() },
+                nft_721_contract_addrs: vec![NFT_CONTRACT.to_string()],
+            }],
+            legacy_staker_collection_ids: vec![COLLECTION_ID.to_string()],
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::LegacyStakeMismatch { .. }));
+    }
+
+    #[test]
+    fn migrate_only_upgrades() {
+        let mut deps = setup_pool(100, 1_000, pool_msg(10, None));
+
+        let migrate_msg = MigrateMsg {
+            legacy_collection_pools: vec![],
+            legacy_staker_collection_ids: vec![],
+        };
+        let err = migrate(deps.as_mut(), env_at(200), migrate_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::CannotMigrateVersion { .. }));
+
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "99.0.0").unwrap();
+        let err = migrate(deps.as_mut(), env_at(200), migrate_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::CannotMigrateVersion { .. }));
+
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.10.2").unwrap();
+        migrate(deps.as_mut(), env_at(200), migrate_msg).unwrap();
+        assert_eq!(get_contract_version(&deps.storage).unwrap().version, CONTRACT_VERSION);
+    }
+
+    /// Contract info update that leaves every field as it is
+    fn info_update() -> UpdateContractInfoMsg {
+        UpdateContractInfoMsg {
+            start_height: None,
+            start_time: None,
+            end_height: None,
+            end_time: None,
+            default_reward_asset: None,
+            fee_collector: None,
+            clear_fee_collector: false,
+            max_pools: None,
+            clear_max_pools: false,
+            incentive_assets: None,
+            max_incentive_streams: None,
+            clear_max_incentive_streams: false,
+        }
+    }
+
+    #[test]
+    fn contract_info_limits_can_be_cleared() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(UpdateContractInfoMsg {
+            fee_collector: Some("fees".to_string()),
+            max_pools: Some(1),
+            ..info_update()
+        })).unwrap();
+        let mut msg = pool_msg(10, None);
+        msg.collection_id = "second".to_string();
+        let err = execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(msg.clone())).unwrap_err();
+        assert!(matches!(err, ContractError::MaxPoolsReached { max_pools: 1 }));
+
+        // Leaving the fields unset keeps them
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(info_update())).unwrap();
+        let contract_info = CONTRACT_INFO.load(&deps.storage).unwrap();
+        assert_eq!(contract_info.fee_collector, Some(Addr::unchecked("fees")));
+        assert_eq!(contract_info.max_pools, Some(1));
+
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(UpdateContractInfoMsg {
+            clear_fee_collector: true,
+            clear_max_pools: true,
+            ..info_update()
+        })).unwrap();
+        let contract_info = CONTRACT_INFO.load(&deps.storage).unwrap();
+        assert_eq!(contract_info.fee_collector, None);
+        assert_eq!(contract_info.max_pools, None);
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::CreateCollectionPool(msg)).unwrap();
+    }
+
+    #[test]
+    fn locked_nfts_cannot_be_withdrawn() {
+        let mut msg = pool_msg(10, None);
+        msg.min_lock_blocks = Some(10);
+        let mut deps = setup_pool(100, 1_000_000, msg);
+        stake(&mut deps, 100, "alice", "1");
+
+        let err = execute(deps.as_mut(), env_at(109), mock_info("alice", &[]), ExecuteMsg::Withdraw {
+            collection_id: COLLECTION_ID.to_string(),
+            withdraw_rewards: true,
+            withdraw_nft_ids: vec![NftId { contract_addr: NFT_CONTRACT.to_string(), token_id: "1".to_string() }],
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NftsLocked { ref locked_tokens } if locked_tokens == "1 unlocks at block 110"));
+
+        let err = execute(deps.as_mut(), env_at(109), mock_info("alice", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NftsLocked { .. }));
+
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "withdraw_nfts"), "1");
+    }
+
+    #[test]
+    fn paused_withdrawals_release_locked_nfts_through_emergency_withdraw() {
+        let mut msg = pool_msg(10, None);
+        msg.min_lock_blocks = Some(u64::MAX);
+        let mut deps = setup_pool(100, 1_000_000, msg);
+        stake(&mut deps, 100, "alice", "1");
+
+        let err = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap_err();
+        assert!(matches!(err, ContractError::NftsLocked { ref locked_tokens } if locked_tokens == &format!("1 unlocks at block {}", u64::MAX)));
+
+        execute(deps.as_mut(), env_at(110), mock_info(ADMIN, &[]), ExecuteMsg::Pause {
+            collection_id: Some(COLLECTION_ID.to_string()),
+            actions: vec![PausableAction::Withdraw],
+        }).unwrap();
+        let res = execute(deps.as_mut(), env_at(110), mock_info("alice", &[]), ExecuteMsg::EmergencyWithdraw {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(attribute(&res.attributes, "withdraw_nfts"), "1");
+    }
+
+    fn penalty_pool_msg(treasury: Option<&str>) -> CreateCollectionPoolMsg {
+        let mut msg = pool_msg(10, None);
+        msg.early_withdraw_penalty = Some(EarlyWithdrawPenaltyMsg {
+            lock_blocks: 100,
+            penalty_rate: Decimal::percent(50),
+            treasury: treasury.map(|treasury| treasury.to_string()),
+        });
+        msg
+    }
+
+    #[test]
+    fn early_withdraw_penalty_defaults_to_the_fee_collector() {
+        let mut deps = setup_pool(100, 1_000_000, penalty_pool_msg(None));
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(UpdateContractInfoMsg {
+            fee_collector: Some("fees".to_string()),
+            ..info_update()
+        })).unwrap();
+        stake(&mut deps, 100, "alice", "1");
+
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "50");
+        assert_eq!(attribute(&res.attributes, "penalty"), "50");
+        assert_eq!(attribute(&res.attributes, "penalty_recipient"), "fees");
+        assert_eq!(res.messages[2].msg, CosmosMsg::Bank(BankMsg::Send {
+            to_address: "fees".to_string(),
+            amount: coins(50, REWARD_DENOM),
+        }));
+    }
+
+    #[test]
+    fn redistributed_penalty_is_fully_claimable() {
+        let mut msg = penalty_pool_msg(None);
+        msg.reward_per_block = Uint128::from(12u128);
+        msg.expired_after = Some(30);
+        let mut deps = setup_pool(100, 1_000, msg);
+        for (staker, token_id) in [("alice", "1"), ("alice", "2"), ("bob", "3"), ("bob", "4")] {
+            stake(&mut deps, 100, staker, token_id);
+        }
+
+        // Alice forfeits half of her 60 while keeping one nft staked, bob's two nfts earn all of it
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "30");
+        assert_eq!(attribute(&res.attributes, "penalty"), "30");
+        assert_eq!(attribute(&res.attributes, "penalty_recipient"), "stakers");
+
+        let alice_claimed = Uint128::from(30u128) + claim(&mut deps, 200, "alice");
+        let bob_claimed = claim(&mut deps, 200, "bob");
+        assert_eq!(alice_claimed, Uint128::from(110u128));
+        assert_eq!(bob_claimed, Uint128::from(250u128));
+
+        let res = execute(deps.as_mut(), env_at(200), mock_info(ADMIN, &[]), ExecuteMsg::ReclaimUnallocatedRewards {
+            collection_id: COLLECTION_ID.to_string(),
+            recipient: ADMIN.to_string(),
+        }).unwrap();
+        let reclaimed = Uint128::from(attribute(&res.attributes, "amount").parse::<u128>().unwrap());
+        assert_eq!(alice_claimed + bob_claimed + reclaimed, Uint128::from(1_000u128));
+    }
+
+    #[test]
+    fn claiming_before_an_early_withdraw_is_penalized() {
+        let mut deps = setup_pool(100, 1_000_000, penalty_pool_msg(Some("treasury")));
+        stake(&mut deps, 100, "alice", "1");
+
+        let res = claim_at(&mut deps, env_at(110), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "50");
+        assert_eq!(attribute(&res.attributes, "penalty"), "50");
+        assert_eq!(attribute(&res.attributes, "penalty_recipient"), "treasury");
+        assert_eq!(res.messages[1].msg, CosmosMsg::Bank(BankMsg::Send {
+            to_address: "treasury".to_string(),
+            amount: coins(50, REWARD_DENOM),
+        }));
+
+        // Nothing is left to withdraw unpenalized in the same block
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "0");
+        assert_eq!(attribute(&res.attributes, "penalty"), "0");
+        assert_eq!(res.messages.len(), 1);
+    }
+
+    #[test]
+    fn rewards_paid_on_deposit_are_penalized_until_the_staked_nfts_leave_the_penalty_period() {
+        let mut deps = setup_pool(100, 1_000_000, penalty_pool_msg(Some("treasury")));
+        stake(&mut deps, 100, "alice", "1");
+
+        let deposit = |token_id: &str| ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: "alice".to_string(),
+            token_id: token_id.to_string(),
+            msg: to_binary(&DepositeMsg {
+                collection_id: COLLECTION_ID.to_string(),
+                withdraw_rewards: true,
+                signature_hash: String::new(),
+            }).unwrap(),
+        });
+        let res = execute(deps.as_mut(), env_at(150), mock_info(NFT_CONTRACT, &[]), deposit("2")).unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "250");
+        assert_eq!(attribute(&res.attributes, "penalty"), "250");
+        assert_eq!(attribute(&res.attributes, "penalty_recipient"), "treasury");
+
+        // Nft 1 left its penalty period at block 200, the newly staked nft 2 has not
+        let res = claim_at(&mut deps, env_at(200), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "250");
+        assert_eq!(attribute(&res.attributes, "penalty"), "250");
+
+        let res = claim_at(&mut deps, env_at(250), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "500");
+        assert_eq!(attribute(&res.attributes, "penalty"), "0");
+        assert_eq!(attribute(&res.attributes, "penalty_recipient"), "none");
+    }
+
+    fn set_token_weight(deps: &mut MockDeps, height: u64, token_id: &str, weight: u128) {
+        execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &[]), ExecuteMsg::SetTokenWeights {
+            collection_id: COLLECTION_ID.to_string(),
+            weights: vec![TokenWeight {
+                contract_addr: NFT_CONTRACT.to_string(),
+                token_id: token_id.to_string(),
+                weight: Uint128::from(weight),
+            }],
+        }).unwrap();
+    }
+
+    #[test]
+    fn weighted_nfts_earn_in_proportion_to_their_weight() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        set_token_weight(&mut deps, 100, "1", 3);
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "bob", "2");
+
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(75u128));
+        assert_eq!(claim(&mut deps, 110, "bob"), Uint128::from(25u128));
+    }
+
+    #[test]
+    fn weight_changes_only_apply_to_nfts_staked_afterwards() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "bob", "2");
+        set_token_weight(&mut deps, 100, "1", 3);
+
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(50u128));
+        assert_eq!(claim(&mut deps, 110, "bob"), Uint128::from(50u128));
+
+        // Staking the nft again picks up its new weight
+        let res = withdraw(&mut deps, 110, "alice", "1");
+        assert_eq!(attribute(&res.attributes, "withdraw_weight"), "1");
+        stake(&mut deps, 110, "alice", "1");
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").staked_tokens[0].weight, Uint128::from(3u128));
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(75u128));
+        assert_eq!(claim(&mut deps, 120, "bob"), Uint128::from(25u128));
+    }
+
+    #[test]
+    fn fractional_set_bonus_is_gained_and_lost_with_the_set() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(35, None));
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::AddSetBonus {
+            collection_id: COLLECTION_ID.to_string(),
+            set_id: "pair".to_string(),
+            requirement: SetRequirementMsg::TokenGroups {
+                contract_addr: NFT_CONTRACT.to_string(),
+                groups: vec![vec!["1".to_string()], vec!["2".to_string()]],
+            },
+            multiplier: Decimal::percent(125),
+        }).unwrap();
+        stake(&mut deps, 100, "bob", "3");
+
+        let res = stake(&mut deps, 100, "alice", "1");
+        assert!(res.attributes.iter().all(|attr| !attr.key.starts_with("set_bonus")));
+        let res = stake(&mut deps, 100, "alice", "2");
+        assert_eq!(attribute(&res.attributes, "set_bonus_gained"), "pair");
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").total_staked, Uint128::from(2_500_000u128));
+
+        // Alice's two nfts earn as two and a half
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(250u128));
+        assert_eq!(claim(&mut deps, 110, "bob"), Uint128::from(100u128));
+
+        let res = withdraw(&mut deps, 110, "alice", "2");
+        assert_eq!(attribute(&res.attributes, "set_bonus_lost"), "pair");
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").total_staked, STAKE_PRECISION);
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(175u128));
+        assert_eq!(claim(&mut deps, 120, "bob"), Uint128::from(175u128));
+    }
+
+    #[test]
+    fn staked_token_tiers_show_the_stake_earned_with() {
+        let mut msg = pool_msg(10, None);
+        msg.loyalty_tiers = Some(vec![LoyaltyTier {
+            min_staked_blocks: 10,
+            multiplier: Decimal::percent(200),
+        }]);
+        let mut deps = setup_pool(100, 1_000_000, msg);
+        stake(&mut deps, 100, "alice", "1");
+
+        let tiers = query_staked_token_tiers(deps.as_ref(), env_at(120), COLLECTION_ID.to_string(), "alice".to_string()).unwrap();
+        assert_eq!(tiers.tokens[0].tier, Some(0));
+        assert_eq!(tiers.tokens[0].multiplier, Decimal::percent(200));
+        // The tier is reached but not picked up yet
+        assert_eq!(tiers.stake, STAKE_PRECISION);
+        assert_eq!(tiers.refreshed_stake, Uint128::from(2u128) * STAKE_PRECISION);
+
+        execute(deps.as_mut(), env_at(120), mock_info("bob", &[]), ExecuteMsg::RefreshStake {
+            collection_id: COLLECTION_ID.to_string(),
+            staker: Some("alice".to_string()),
+        }).unwrap();
+        let tiers = query_staked_token_tiers(deps.as_ref(), env_at(120), COLLECTION_ID.to_string(), "alice".to_string()).unwrap();
+        assert_eq!(tiers.stake, Uint128::from(2u128) * STAKE_PRECISION);
+    }
+
+    #[test]
+    fn fractional_loyalty_tiers_raise_the_stake() {
+        let mut msg = pool_msg(10, None);
+        msg.loyalty_tiers = Some(vec![LoyaltyTier {
+            min_staked_blocks: 10,
+            multiplier: Decimal::percent(150),
+        }]);
+        let mut deps = setup_pool(100, 1_000_000, msg);
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "bob", "2");
+
+        let tiers = query_staked_token_tiers(deps.as_ref(), env_at(110), COLLECTION_ID.to_string(), "alice".to_string()).unwrap();
+        assert_eq!(tiers.refreshed_stake, Uint128::from(1_500_000u128));
+
+        // Alice's claim picks up the tier, bob keeps earning at his old stake until his is recalculated
+        assert_eq!(claim(&mut deps, 110, "alice"), Uint128::from(50u128));
+        assert_eq!(load_staker(&deps, COLLECTION_ID, "alice").total_staked, Uint128::from(1_500_000u128));
+        assert_eq!(claim(&mut deps, 120, "alice"), Uint128::from(60u128));
+        assert_eq!(claim(&mut deps, 120, "bob"), Uint128::from(90u128));
+    }
+
+    /// Adds a reward stream paying `denom` and funds it with `amount`, which the contract then holds
+    fn add_funded_stream(deps: &mut MockDeps, height: u64, denom: &str, reward_per_block: u128, end_block: u64, amount: u128) -> u64 {
+        let res = execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &[]), ExecuteMsg::AddRewardStream {
+            collection_id: COLLECTION_ID.to_string(),
+            reward_asset: RewardAssetInfo::Native { denom: denom.to_string() },
+            reward_per_block: Uint128::from(reward_per_block),
+            end_block,
+        }).unwrap();
+        let stream_id = attribute(&res.attributes, "stream_id").parse().unwrap();
+        execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &coins(amount, denom)), ExecuteMsg::FundRewardStream {
+            collection_id: COLLECTION_ID.to_string(),
+            stream_id,
+        }).unwrap();
+        set_contract_balance(deps, denom, amount);
+        stream_id
+    }
+
+    #[test]
+    fn claim_pays_every_reward_asset_including_streams_added_after_the_stake() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        assert_eq!(add_funded_stream(&mut deps, 104, "bonus", 5, 200, 1_000), 0);
+        assert_eq!(add_funded_stream(&mut deps, 106, "extra", 2, 200, 1_000), 1);
+
+        let res = claim_at(&mut deps, env_at(110), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_rewards"), "100");
+        let stream_rewards = res.attributes
+            .iter()
+            .filter(|attr| attr.key == "claimed_stream_rewards")
+            .map(|attr| attr.value.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(stream_rewards, vec!["0:30", "1:8"]);
+        let sent = res.messages
+            .iter()
+            .map(|msg| match &msg.msg {
+                CosmosMsg::Bank(BankMsg::Send { to_address, amount }) if to_address == "alice" => amount[0].clone(),
+                msg => panic!("unexpected message {:?}", msg),
+            })
+            .collect::<Vec<Coin>>();
+        assert_eq!(sent, vec![Coin::new(30, "bonus"), Coin::new(8, "extra"), Coin::new(100, REWARD_DENOM)]);
+    }
+
+    #[test]
+    fn ended_stream_budget_can_be_reclaimed() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        stake(&mut deps, 100, "alice", "1");
+        let stream_id = add_funded_stream(&mut deps, 100, "bonus", 5, 110, 200);
+        let reclaim = ExecuteMsg::ReclaimUnallocatedStreamRewards {
+            collection_id: COLLECTION_ID.to_string(),
+            stream_id,
+            recipient: ADMIN.to_string(),
+        };
+
+        let err = execute(deps.as_mut(), env_at(105), mock_info(ADMIN, &[]), reclaim.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::RewardStreamNotEnded {}));
+
+        // Only the budget never accrued to stakers is reclaimed
+        let res = execute(deps.as_mut(), env_at(120), mock_info(ADMIN, &[]), reclaim.clone()).unwrap();
+        assert_eq!(attribute(&res.attributes, "amount"), "150");
+        assert_eq!(res.messages[0].msg, CosmosMsg::Bank(BankMsg::Send {
+            to_address: ADMIN.to_string(),
+            amount: coins(150, "bonus"),
+        }));
+        set_contract_balance(&mut deps, "bonus", 50);
+        let err = execute(deps.as_mut(), env_at(120), mock_info(ADMIN, &[]), reclaim).unwrap_err();
+        assert!(matches!(err, ContractError::NoUnallocatedRewards {}));
+
+        let res = claim_at(&mut deps, env_at(120), "alice").unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_stream_rewards"), "0:50");
+    }
+
+    const INCENTIVE_DENOM: &str = "incentive";
+
+    /// Accepts `INCENTIVE_DENOM` incentive streams and gives the contract `balance` of it on top of the reward budget.
+    fn accept_incentives(deps: &mut MockDeps, height: u64, max_incentive_streams: Option<u32>, balance: u128) {
+        execute(deps.as_mut(), env_at(height), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(UpdateContractInfoMsg {
+            incentive_assets: Some(vec![IncentiveAssetInfo {
+                reward_asset: RewardAssetInfo::Native { denom: INCENTIVE_DENOM.to_string() },
+                min_deposit: Uint128::from(1u128),
+            }]),
+            max_incentive_streams,
+            ..info_update()
+        })).unwrap();
+        set_contract_balance(deps, INCENTIVE_DENOM, balance);
+    }
+
+    fn create_incentive(deps: &mut MockDeps, height: u64, amount: u128, reward_per_block: u128, duration_blocks: u64) -> Result<Response, ContractError> {
+        execute(deps.as_mut(), env_at(height), mock_info("funder", &coins(amount, INCENTIVE_DENOM)), ExecuteMsg::CreateIncentiveStream {
+            collection_id: COLLECTION_ID.to_string(),
+            reward_per_block: Uint128::from(reward_per_block),
+            duration_blocks,
+        })
+    }
+
+    #[test]
+    fn settled_incentive_streams_are_removed() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        accept_incentives(&mut deps, 100, Some(1), 100);
+        stake(&mut deps, 100, "alice", "1");
+        stake(&mut deps, 100, "bob", "2");
+        create_incentive(&mut deps, 100, 100, 10, 5).unwrap();
+
+        // Ended streams keep their slot until every stake settled against them
+        let err = create_incentive(&mut deps, 110, 100, 10, 5).unwrap_err();
+        assert!(matches!(err, ContractError::MaxIncentiveStreamsReached { max_incentive_streams: 1 }));
+        assert_eq!(load_pool(&deps).reward_streams[0].unsettled_stake, Some(Uint128::from(2u128) * STAKE_PRECISION));
+
+        claim(&mut deps, 110, "alice");
+        execute(deps.as_mut(), env_at(110), mock_info("anyone", &[]), ExecuteMsg::RefreshStake {
+            collection_id: COLLECTION_ID.to_string(),
+            staker: Some("bob".to_string()),
+        }).unwrap();
+        let res = execute(deps.as_mut(), env_at(110), mock_info("anyone", &[]), ExecuteMsg::RefundEndedIncentiveStreams {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(attribute(&res.attributes, "removed_stream"), "0");
+        assert!(load_pool(&deps).reward_streams.is_empty());
+
+        // Bob is still paid from the removed stream
+        let pending = query_pending_reward(deps.as_ref(), env_at(110), COLLECTION_ID.to_string(), "bob".to_string()).unwrap();
+        assert_eq!(pending.stream_pending[0].pending, Uint128::from(25u128));
+        let res = execute(deps.as_mut(), env_at(110), mock_info("bob", &[]), ExecuteMsg::Claim {
+            collection_id: COLLECTION_ID.to_string(),
+        }).unwrap();
+        assert_eq!(attribute(&res.attributes, "claimed_stream_rewards"), "0:25");
+        assert!(load_staker(&deps, COLLECTION_ID, "bob").stream_rewards.is_empty());
+
+        create_incentive(&mut deps, 110, 100, 10, 5).unwrap();
+    }
+
+    #[test]
+    fn incentive_stream_limit_can_be_cleared() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        accept_incentives(&mut deps, 100, Some(1), 200);
+        create_incentive(&mut deps, 100, 100, 10, 10).unwrap();
+        let err = create_incentive(&mut deps, 100, 100, 10, 10).unwrap_err();
+        assert!(matches!(err, ContractError::MaxIncentiveStreamsReached { max_incentive_streams: 1 }));
+
+        execute(deps.as_mut(), env_at(100), mock_info(ADMIN, &[]), ExecuteMsg::UpdateContractInfo(UpdateContractInfoMsg {
+            clear_max_incentive_streams: true,
+            ..info_update()
+        })).unwrap();
+        assert_eq!(CONTRACT_INFO.load(&deps.storage).unwrap().max_incentive_streams, None);
+        create_incentive(&mut deps, 100, 100, 10, 10).unwrap();
+        assert_eq!(load_pool(&deps).reward_streams.len(), 2);
+    }
+
+    #[test]
+    fn incentive_stream_end_block_overflow_is_rejected() {
+        let mut deps = setup_pool(100, 1_000_000, pool_msg(10, None));
+        accept_incentives(&mut deps, 100, None, 100);
+
+        let err = create_incentive(&mut deps, 100, 100, 10, u64::MAX).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidRewardStream {}));
+    }
+}
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,24 +6,120 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("Cannot migrate from a different contract ({previous_contract})")]
+    CannotMigrate { previous_contract: String },
+
+    #[error("Cannot migrate from version {previous_version} to {new_version}, only upgrades are allowed")]
+    CannotMigrateVersion {
+        previous_version: String,
+        new_version: String,
+    },
+
     #[error("Unauthorized")]
     Unauthorized { sender: String },
 
-    #[error("Escrow expired (end_height {end_height:?} end_time {end_time:?})")]
+    #[error("There is no pending admin proposal")]
+    NoAdminProposal {},
+
+    #[error("Staking program ended (end_height {end_height:?} end_time {end_time:?})")]
     Expired {
         end_height: Option<u64>,
         end_time: Option<u64>,
     },
 
-    #[error("Escrow not expired")]
-    NotExpired {},
+    #[error("Staking program not started (start_height {start_height:?} start_time {start_time:?})")]
+    NotStarted {
+        start_height: Option<u64>,
+        start_time: Option<u64>,
+    },
+
+    #[error("Maximum number of collection pools reached ({max_pools})")]
+    MaxPoolsReached { max_pools: u32 },
+
+    #[error("No reward asset given and no default reward asset configured")]
+    MissingRewardAsset {},
+
+    #[error("{action} is paused")]
+    Paused { action: String },
 
     #[error("Reward per block must be greater than 0")]
     InvalidRewardPerBlock {},
 
+    #[error("Reward per second must be greater than 0")]
+    InvalidRewardPerSecond {},
+
+    #[error("Emission end time must be after its start time")]
+    InvalidEmissionTime {},
+
+    #[error("Time based pools end at their end time, expired_after only applies to block based pools")]
+    InvalidExpiredAfter {},
+
+    #[error("Block based pools end at their expired block, start_time and end_time only apply to time based pools")]
+    InvalidTimeBounds {},
+
+    #[error("Reward schedule entries must start after the current block in increasing order with a positive reward per block")]
+    InvalidRewardSchedule {},
+
     #[error("There is no reward pool for this collection")]
     InvalidCollection {},
 
+    #[error("Nfts from {contract_addr} can not be staked into collection {collection_id}")]
+    InvalidCollectionNftContract {
+        collection_id: String,
+        contract_addr: String,
+    },
+
+    #[error("Legacy stakes exceed the staked nfts of collection {collection_id}")]
+    LegacyStakeMismatch { collection_id: String },
+
+    #[error("Penalty rate must be at most 1")]
+    InvalidPenaltyRate {},
+
+    #[error("Reward stream needs a reward per block and an end block in the future")]
+    InvalidRewardStream {},
+
+    #[error("Reward stream {stream_id} not found")]
+    RewardStreamNotFound { stream_id: u64 },
+
+    #[error("Reward stream has not ended yet")]
+    RewardStreamNotEnded {},
+
+    #[error("Incentive streams can't be opened with this asset")]
+    IncentiveAssetNotAccepted {},
+
+    #[error("Incentive stream deposit must be at least {min_deposit}")]
+    IncentiveDepositTooLow { min_deposit: Uint128 },
+
+    #[error("Collection pool already holds {max_incentive_streams} incentive streams")]
+    MaxIncentiveStreamsReached { max_incentive_streams: u32 },
+
+    #[error("Loyalty tiers must be ordered by min_staked_blocks with multipliers of at least 1")]
+    InvalidLoyaltyTiers {},
+
+    #[error("Set bonus needs a multiplier of at least 1 and at least one requirement")]
+    InvalidSetBonus {},
+
+    #[error("Nfts are still locked: {locked_tokens}")]
+    NftsLocked { locked_tokens: String },
+
     #[error("Collection expired")]
     ExpiredCollection {},
+
+    #[error("Collection not expired")]
+    CollectionNotExpired {},
+
+    #[error("There are no unallocated rewards to reclaim")]
+    NoUnallocatedRewards {},
+
+    #[error("There are no pending rewards to claim")]
+    NoPendingRewards {},
+
+    #[error("Funds must be a positive amount of the pool's reward asset")]
+    InvalidRewardFunds {},
+
+    #[error("Insufficient reward balance (needed {needed} available {available})")]
+    InsufficientRewardBalance {
+        needed: Uint128,
+        available: Uint128,
+    },
 }
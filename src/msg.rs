@@ -1,65 +1,305 @@
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Decimal, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use cw20::Cw20ReceiveMsg;
 use cw721::Cw721ReceiveMsg;
 
+use crate::state::{
+    CollectionPoolInfo, ContractInfo, EmissionMode, PausableAction, PauseInfo, RewardScheduleEntry, LoyaltyTier, RewardAsset, Role, SetBonus, StakerInfo,
+};
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
-    pub arbiter: String,
-    pub recipient: String,
-    /// When end height set and block height exceeds this value, the escrow is expired.
-    /// Once an escrow is expired, it can be returned to the original funder (via "refund").
+    /// When start height is set, nfts can't be staked before the block height reaches this value.
+    pub start_height: Option<u64>,
+    /// When start time (in seconds since epoch 00:00:00 UTC on 1 January 1970) is set,
+    /// nfts can't be staked before the block time reaches this value.
+    pub start_time: Option<u64>,
+    /// When end height is set and block height exceeds this value, the staking program is over.
+    /// Staked nfts and their rewards can still be withdrawn, but no new nfts can be staked.
     pub end_height: Option<u64>,
     /// When end time (in seconds since epoch 00:00:00 UTC on 1 January 1970) is set and
-    /// block time exceeds this value, the escrow is expired.
-    /// Once an escrow is expired, it can be returned to the original funder (via "refund").
+    /// block time exceeds this value, the staking program is over.
     pub end_time: Option<u64>,
     pub admin: Option<String>,
-    pub nft_721_contract_addr_whitelist: Vec<String>
+    pub nft_721_contract_addr_whitelist: Vec<String>,
+    /// Reward asset of pools created without one
+    pub default_reward_asset: Option<RewardAssetInfo>,
+    /// Receives the early withdrawal penalties of pools without their own treasury
+    pub fee_collector: Option<String>,
+    /// Maximum number of collection pools, unlimited when unset
+    pub max_pools: Option<u32>,
+    /// Assets anyone can open an incentive stream with, none when unset
+    pub incentive_assets: Option<Vec<IncentiveAssetInfo>>,
+    /// Maximum number of incentive streams per pool, unlimited when unset.
+    /// Ended streams count until they are refunded and every stake settled against them.
+    pub max_incentive_streams: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
-    // Approve {
-    //     // release some coins - if quantity is None, release all coins in balance
-    //     quantity: Option<Vec<Coin>>,
-    // },
-    // Refund {},
     UpdateContractInfo(UpdateContractInfoMsg),
+    /// Proposes a new admin, who takes over only after calling `AcceptAdmin`
+    ProposeNewAdmin {
+        new_admin: String,
+    },
+    AcceptAdmin {},
+    CancelAdminProposal {},
+    /// Removes the admin for good, no admin handler can be called afterwards
+    RenounceAdmin {},
+    GrantRole {
+        role: Role,
+        address: String,
+    },
+    RevokeRole {
+        role: Role,
+        address: String,
+    },
+    /// Pauses `actions` for every pool, or only for `collection_id` when set
+    Pause {
+        collection_id: Option<String>,
+        actions: Vec<PausableAction>,
+    },
+    /// Lifts a pause set at the same scope
+    Unpause {
+        collection_id: Option<String>,
+        actions: Vec<PausableAction>,
+    },
+    /// Allows nfts from these cw721 contracts to be staked
+    AddToWhitelist {
+        contract_addrs: Vec<String>,
+    },
+    /// Stops accepting new stakes from these cw721 contracts, staked nfts can still be withdrawn
+    RemoveFromWhitelist {
+        contract_addrs: Vec<String>,
+    },
     CreateCollectionPool(CreateCollectionPoolMsg),
     UpdateCollectionPool(UpdateCollectionPoolMsg),
+    /// Adds a set bonus to `collection_id`, replacing the one with the same `set_id`.
+    /// Stakers gain or lose set bonuses on their next deposit or withdrawal.
+    AddSetBonus {
+        collection_id: String,
+        set_id: String,
+        requirement: SetRequirementMsg,
+        multiplier: Decimal,
+    },
+    RemoveSetBonus {
+        collection_id: String,
+        set_id: String,
+    },
+    /// Adds a block based reward stream to `collection_id`, paid next to its main reward.
+    /// The stream accrues from the current block up to `end_block` once funded.
+    AddRewardStream {
+        collection_id: String,
+        reward_asset: RewardAssetInfo,
+        reward_per_block: Uint128,
+        end_block: u64,
+    },
+    /// Sets the staking weight of nfts in `collection_id`, a zero weight resets it to the default of 1.
+    /// Only applies to nfts staked afterwards.
+    SetTokenWeights {
+        collection_id: String,
+        weights: Vec<TokenWeight>,
+    },
     ReceiveNft(Cw721ReceiveMsg),
+    /// Withdraws the listed nfts, identified by their contract as token ids can repeat across the pool's contracts
     Withdraw {
         collection_id: String,
         withdraw_rewards: bool,
-        withdraw_nft_ids: Vec<String>,
+        withdraw_nft_ids: Vec<NftId>,
+    },
+    /// Rewrites a collection pool saved before reward assets and the scaled accumulator existed.
+    /// Pools must be migrated before their stakers.
+    MigrateCollectionPool {
+        collection_id: String,
+        reward_asset: RewardAssetInfo,
+        nft_721_contract_addrs: Vec<String>,
+    },
+    /// Moves the nfts of staker records saved before staking was scoped per pool into `collection_id`.
+    /// Only nfts from contracts bound to `collection_id` are moved, so every pool has to be migrated in turn.
+    MigrateStakerInfo {
+        collection_id: String,
+        limit: Option<u32>,
+    },
+    /// Returns every nft the sender staked in `collection_id` without settling rewards.
+    /// Pending rewards are forfeited and go back to the budgets they were accrued from, where they can be reclaimed.
+    /// The pool accrues up to the current block first, skipping the blocks since its last update only if that overflows.
+    /// This is never paused, and ignores `min_lock_blocks` while withdrawals are paused.
+    EmergencyWithdraw {
+        collection_id: String,
+    },
+    /// Settles the rewards of `staker` (the sender when unset) and recalculates their stake
+    /// with the current loyalty tiers and set bonuses. Anyone can call it.
+    RefreshStake {
+        collection_id: String,
+        staker: Option<String>,
+    },
+    /// Pays out the sender's pending rewards in `collection_id` without withdrawing any nft
+    Claim {
+        collection_id: String,
+    },
+    /// Adds the attached native funds to the reward budget of `collection_id`
+    FundPool {
+        collection_id: String,
+    },
+    /// Opens an incentive stream on `collection_id` funded with the attached native funds, anyone can call it.
+    /// Whatever was not accrued to stakers is refunded to the sender once the stream ended.
+    CreateIncentiveStream {
+        collection_id: String,
+        reward_per_block: Uint128,
+        duration_blocks: u64,
+    },
+    /// Refunds the unallocated budget of every ended incentive stream in `collection_id`, anyone can call it.
+    /// Refunded streams are removed once every stake settled against them, which `RefreshStake` does for idle stakers.
+    /// Deposits, withdrawals and claims in the pool do the same.
+    RefundEndedIncentiveStreams {
+        collection_id: String,
+    },
+    /// Adds the attached native funds to the budget of a reward stream
+    FundRewardStream {
+        collection_id: String,
+        stream_id: u64,
+    },
+    /// Adds cw20 tokens to a reward budget, see `Cw20HookMsg`
+    Receive(Cw20ReceiveMsg),
+    /// Sends the part of an expired pool's budget that was never accrued to stakers to `recipient`
+    ReclaimUnallocatedRewards {
+        collection_id: String,
+        recipient: String,
+    },
+    /// Sends the part of an ended reward stream's budget that was never accrued to stakers to `recipient`
+    ReclaimUnallocatedStreamRewards {
+        collection_id: String,
+        stream_id: u64,
+        recipient: String,
     },
-    // Claim {
-    //     collection_id: String,
-    // },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct UpdateContractInfoMsg {
-    pub nft_721_contract_addr_whitelist: Option<Vec<String>>,
-    pub admin: Option<String>,
+    pub start_height: Option<u64>,
+    pub start_time: Option<u64>,
+    pub end_height: Option<u64>,
+    pub end_time: Option<u64>,
+    pub default_reward_asset: Option<RewardAssetInfo>,
+    pub fee_collector: Option<String>,
+    /// Removes the fee collector, overrides `fee_collector`
+    #[serde(default)]
+    pub clear_fee_collector: bool,
+    pub max_pools: Option<u32>,
+    /// Removes the pool limit, overrides `max_pools`
+    #[serde(default)]
+    pub clear_max_pools: bool,
+    /// Replaces the accepted incentive assets, running streams are not affected
+    pub incentive_assets: Option<Vec<IncentiveAssetInfo>>,
+    pub max_incentive_streams: Option<u32>,
+    /// Removes the incentive stream limit, overrides `max_incentive_streams`
+    #[serde(default)]
+    pub clear_max_incentive_streams: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct IncentiveAssetInfo {
+    pub reward_asset: RewardAssetInfo,
+    pub min_deposit: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct CreateCollectionPoolMsg {
     pub collection_id: String,
+    /// The cw721 contracts whose nfts can be staked into this pool
+    pub nft_721_contract_addrs: Vec<String>,
     pub reward_per_block: Uint128,
+    /// Blocks after which the pool expires, only for `EmissionMode::Block`
     pub expired_after: Option<u64>,
+    /// Blocks an nft has to stay staked before it can be withdrawn
+    pub min_lock_blocks: Option<u64>,
+    pub early_withdraw_penalty: Option<EarlyWithdrawPenaltyMsg>,
+    /// Defaults to the contract's `default_reward_asset`
+    pub reward_asset: Option<RewardAssetInfo>,
+    /// Defaults to block based emission using `reward_per_block`
+    #[serde(default)]
+    pub emission_mode: EmissionMode,
+    /// Required for `EmissionMode::Time`
+    pub reward_per_second: Option<Uint128>,
+    /// Seconds since epoch, defaults to the current block time. Only for `EmissionMode::Time`
+    pub start_time: Option<u64>,
+    /// Seconds since epoch, rewards accrue forever when unset. Only for `EmissionMode::Time`
+    pub end_time: Option<u64>,
+    /// Planned `reward_per_block` changes for `EmissionMode::Block`
+    pub reward_schedule: Option<Vec<RewardScheduleEntry>>,
+    /// Multipliers for nfts staked long enough, ordered by `min_staked_blocks`
+    pub loyalty_tiers: Option<Vec<LoyaltyTier>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SetRequirementMsg {
+    /// At least one staked nft from each contract
+    Contracts { contract_addrs: Vec<String> },
+    /// At least one staked nft of `contract_addr` from each group of token ids
+    TokenGroups {
+        contract_addr: String,
+        groups: Vec<Vec<String>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TokenWeight {
+    pub contract_addr: String,
+    pub token_id: String,
+    pub weight: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct EarlyWithdrawPenaltyMsg {
+    /// Blocks after staking during which withdrawing an nft, or being paid rewards while it is staked, is penalized
+    pub lock_blocks: u64,
+    /// Share of the staker's pending rewards that is forfeited, at most 1
+    pub penalty_rate: Decimal,
+    /// Receives the forfeited rewards, defaults to the contract's `fee_collector`.
+    /// They are split among the remaining stakers when neither is set.
+    pub treasury: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RewardAssetInfo {
+    /// Rewards are paid in a native denom held by the contract
+    Native { denom: String },
+    /// Rewards are paid in a cw20 token held by the contract
+    Cw20 { contract_addr: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct UpdateCollectionPoolMsg {
     pub collection_id: String,
+    /// Replaces the accepted cw721 contracts, nfts already staked can still be withdrawn
+    pub nft_721_contract_addrs: Option<Vec<String>>,
     pub reward_per_block: Option<Uint128>,
+    pub reward_per_second: Option<Uint128>,
+    /// Applies to nfts already staked as well
+    pub min_lock_blocks: Option<u64>,
+    /// A zero `penalty_rate` removes the penalty
+    pub early_withdraw_penalty: Option<EarlyWithdrawPenaltyMsg>,
+    /// Replaces the planned `reward_per_block` changes
+    pub reward_schedule: Option<Vec<RewardScheduleEntry>>,
+    /// Replaces the loyalty tiers, staked nfts pick them up when their staker's stake is recalculated
+    pub loyalty_tiers: Option<Vec<LoyaltyTier>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct NftId {
+    pub contract_addr: String,
+    pub token_id: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -70,14 +310,189 @@ pub struct DepositeMsg {
     pub signature_hash: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Cw20HookMsg {
+    /// Adds the received cw20 tokens to the reward budget of `collection_id`
+    FundPool {
+        collection_id: String,
+    },
+    /// Adds the received cw20 tokens to the budget of a reward stream
+    FundRewardStream {
+        collection_id: String,
+        stream_id: u64,
+    },
+    /// Opens an incentive stream funded with the received cw20 tokens, see `ExecuteMsg::CreateIncentiveStream`
+    CreateIncentiveStream {
+        collection_id: String,
+        reward_per_block: Uint128,
+        duration_blocks: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct MigrateMsg {
+    /// Pools to rewrite when migrating from the unversioned layout
+    #[serde(default)]
+    pub legacy_collection_pools: Vec<LegacyCollectionPoolMigration>,
+    /// Pools that take over the nfts of every staker record of the unversioned layout, see `ExecuteMsg::MigrateStakerInfo`.
+    /// Leave empty to move them in batches instead.
+    #[serde(default)]
+    pub legacy_staker_collection_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct LegacyCollectionPoolMigration {
+    pub collection_id: String,
+    pub reward_asset: RewardAssetInfo,
+    pub nft_721_contract_addrs: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    /// Returns a human-readable representation of the arbiter.
-    Arbiter {},
+    /// Returns the contract configuration
+    ContractInfo {},
+    /// Returns the paused actions for every pool, or only for `collection_id` when set
+    PauseInfo { collection_id: Option<String> },
+    /// Lists the addresses holding `role`
+    RoleHolders {
+        role: Role,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the whitelisted cw721 contracts
+    Whitelist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Returns a single collection pool
+    CollectionPool { collection_id: String },
+    /// Lists collection pools ordered by collection_id
+    ListCollectionPools {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the set bonuses of a collection pool ordered by set_id
+    SetBonuses {
+        collection_id: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Returns the weight an nft would be staked with in a collection pool
+    TokenWeight {
+        collection_id: String,
+        contract_addr: String,
+        token_id: String,
+    },
+    /// Returns a staker's position in a collection pool
+    StakerInfo {
+        collection_id: String,
+        staker: String,
+    },
+    /// Returns the loyalty tier of each nft a staker has staked in a collection pool
+    StakedTokenTiers {
+        collection_id: String,
+        staker: String,
+    },
+    /// Returns the rewards a staker could claim at the current block
+    PendingReward {
+        collection_id: String,
+        staker: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ContractInfoResponse {
+    pub contract_info: ContractInfo,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PauseInfoResponse {
+    pub collection_id: Option<String>,
+    pub pause_info: PauseInfo,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RoleHoldersResponse {
+    pub role: Role,
+    pub holders: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WhitelistResponse {
+    pub contract_addrs: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CollectionPoolResponse {
+    pub collection_pool_info: CollectionPoolInfo,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ListCollectionPoolsResponse {
+    pub collection_pools: Vec<CollectionPoolInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SetBonusesResponse {
+    pub collection_id: String,
+    pub set_bonuses: Vec<SetBonus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenWeightResponse {
+    pub collection_id: String,
+    pub contract_addr: Addr,
+    pub token_id: String,
+    pub weight: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StakerInfoResponse {
+    pub collection_id: String,
+    pub staker: Addr,
+    pub staker_info: StakerInfo,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StakedTokenTiersResponse {
+    pub collection_id: String,
+    pub staker: Addr,
+    /// Stake the staker currently earns with, tiers reached since it was last recalculated are not included
+    pub stake: Uint128,
+    /// Stake after recalculating it at the current block, which a deposit, claim or `RefreshStake` does
+    pub refreshed_stake: Uint128,
+    /// Tiers as of the current block, they only apply to `stake` once it is recalculated
+    pub tokens: Vec<StakedTokenTier>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StakedTokenTier {
+    pub token_id: String,
+    pub contract_addr: Addr,
+    pub staked_at: u64,
+    /// Index into the pool's `loyalty_tiers`, unset before the first tier is reached
+    pub tier: Option<u32>,
+    pub multiplier: Decimal,
+    /// Block height at which the next tier is reached, unset at the highest tier
+    pub next_tier_at: Option<u64>,
+    pub next_tier_multiplier: Option<Decimal>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingRewardResponse {
+    pub collection_id: String,
+    pub staker: Addr,
+    pub pending: Uint128,
+    pub stream_pending: Vec<StreamPendingReward>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct ArbiterResponse {
-    pub arbiter: Addr,
+pub struct StreamPendingReward {
+    pub stream_id: u64,
+    pub reward_asset: RewardAsset,
+    pub pending: Uint128,
 }
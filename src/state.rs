@@ -1,24 +1,191 @@
-use cosmwasm_std::{Addr, Env, Uint128};
+use cosmwasm_std::{Addr, Decimal, Env, Uint128};
 use cw_storage_plus::{Item, Map};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-pub const CONTRACT_INFO: Item<ContractInfo> = Item::new("contract_info");
+pub const CONTRACT_INFO: Item<ContractInfo> = Item::new("staking_contract_info");
 
-pub const COLLECTION_POOL_INFO: Map<&[u8], CollectionPoolInfo> = Map::new("collection_pool_info_map");
+/// Contract info saved before cw2 version tracking, whose "contract_info" key cw2 now uses.
+/// Rewritten into `CONTRACT_INFO` by the `migrate` entry point.
+pub const LEGACY_CONTRACT_INFO: Item<LegacyContractInfo> = Item::new("contract_info");
 
-pub const STAKING_INFO: Map<&[u8], StakerInfo> = Map::new("staker_info_map");
+/// Operator roles keyed by (role, address) so the holders of a role can be listed by prefix.
+pub const ROLES: Map<(&str, &Addr), ()> = Map::new("roles");
+
+/// Actions paused for every collection pool.
+pub const CONTRACT_PAUSE_INFO: Item<PauseInfo> = Item::new("contract_pause_info");
+
+/// Actions paused for a single collection pool, on top of `CONTRACT_PAUSE_INFO`.
+pub const COLLECTION_PAUSE_INFO: Map<&[u8], PauseInfo> = Map::new("collection_pause_info_map");
+
+/// Validated cw721 contracts allowed to send nfts to this contract.
+pub const NFT_721_CONTRACT_WHITELIST: Map<&Addr, ()> = Map::new("nft_721_contract_whitelist");
+
+/// Scaling factor applied to `CollectionPoolInfo.acc_per_share` so small rewards spread over many nfts don't truncate to zero.
+/// It covers the `STAKE_PRECISION` of the stakes it is multiplied with, leaving 1e12 per nft.
+pub const ACC_PER_SHARE_PRECISION: Uint128 = Uint128::new(1_000_000_000_000_000_000);
+
+pub const COLLECTION_POOL_INFO: Map<&[u8], CollectionPoolInfo> = Map::new("collection_pool_map");
+
+/// Collection pools saved before reward assets and the scaled accumulator existed.
+/// Records are moved into `COLLECTION_POOL_INFO` by the `migrate` entry point or `ExecuteMsg::MigrateCollectionPool`.
+pub const LEGACY_COLLECTION_POOL_INFO: Map<&[u8], LegacyCollectionPoolInfo> = Map::new("collection_pool_info_map");
+
+/// Staking weight of a single nft in a collection pool, keyed by (collection_id, contract_addr, token_id).
+/// Nfts without an entry weigh `DEFAULT_TOKEN_WEIGHT`.
+pub const TOKEN_WEIGHTS: Map<(&[u8], &Addr, &str), Uint128> = Map::new("token_weights");
+
+pub const DEFAULT_TOKEN_WEIGHT: Uint128 = Uint128::new(1);
+
+/// Stake of one unit of nft weight, so fractional loyalty and set bonus multipliers don't round down.
+pub const STAKE_PRECISION: Uint128 = Uint128::new(1_000_000);
+
+/// Set bonuses of a collection pool keyed by (collection_id, set_id).
+pub const SET_BONUSES: Map<(&[u8], &str), SetBonus> = Map::new("set_bonuses");
+
+/// Staker state scoped to a single collection pool, keyed by (collection_id, staker).
+pub const STAKING_INFO: Map<(&[u8], &[u8]), StakerInfo> = Map::new("collection_staker_info_map");
+
+/// Staker state from before it was scoped per collection pool, keyed by staker only.
+/// Records are moved into `STAKING_INFO` by the `migrate` entry point or `ExecuteMsg::MigrateStakerInfo`.
+pub const LEGACY_STAKING_INFO: Map<&[u8], StakerInfo> = Map::new("staker_info_map");
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ContractInfo {
+    pub source: Addr,
+    /// Bounds of the staking program, nfts can only be deposited in between
+    #[serde(default)]
+    pub start_height: Option<u64>,
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    pub end_height: Option<u64>,
+    pub end_time: Option<u64>,
+    /// Unset once the admin renounced, which disables every admin handler
+    pub admin: Option<Addr>,
+    /// Proposed admin that still has to accept the role
+    #[serde(default)]
+    pub pending_admin: Option<Addr>,
+    /// Reward asset of pools created without one
+    #[serde(default)]
+    pub default_reward_asset: Option<RewardAsset>,
+    /// Receives the early withdrawal penalties of pools without their own treasury
+    #[serde(default)]
+    pub fee_collector: Option<Addr>,
+    /// Maximum number of collection pools, unlimited when unset
+    #[serde(default)]
+    pub max_pools: Option<u32>,
+    /// Assets anyone can open an incentive stream with, along with the minimum deposit
+    #[serde(default)]
+    pub incentive_assets: Vec<IncentiveAsset>,
+    /// Maximum number of incentive streams per pool, unlimited when unset.
+    /// Ended streams count until they are refunded and every stake settled against them.
+    #[serde(default)]
+    pub max_incentive_streams: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct IncentiveAsset {
+    pub reward_asset: RewardAsset,
+    pub min_deposit: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyContractInfo {
     pub source: Addr,
     pub end_height: Option<u64>,
     pub end_time: Option<u64>,
     pub admin: Option<String>,
-    pub nft_721_contract_addr_whitelist: Vec<String>
+    pub nft_721_contract_addr_whitelist: Vec<String>,
+}
+
+/// Operator roles the admin can grant next to its own permissions.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Creates and updates collection pools
+    PoolManager,
+    /// Adds and removes whitelisted cw721 contracts
+    WhitelistManager,
+    /// Pauses and unpauses staking
+    Pauser,
+    /// Funds pools and reclaims unallocated rewards
+    Treasurer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::PoolManager => "pool_manager",
+            Role::WhitelistManager => "whitelist_manager",
+            Role::Pauser => "pauser",
+            Role::Treasurer => "treasurer",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PausableAction {
+    /// Staking nfts through `ReceiveNft`
+    Deposit,
+    /// Paying out rewards, whether through `Claim`, `Withdraw` or a deposit
+    Claim,
+    /// Withdrawing nfts through `Withdraw`, `EmergencyWithdraw` stays available as the exit
+    Withdraw,
+}
+
+impl PausableAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PausableAction::Deposit => "deposit",
+            PausableAction::Claim => "claim",
+            PausableAction::Withdraw => "withdraw",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema, Debug)]
+pub struct PauseInfo {
+    pub deposit: bool,
+    pub claim: bool,
+    pub withdraw: bool,
+}
+
+impl PauseInfo {
+    pub fn is_paused(&self, action: PausableAction) -> bool {
+        match action {
+            PausableAction::Deposit => self.deposit,
+            PausableAction::Claim => self.claim,
+            PausableAction::Withdraw => self.withdraw,
+        }
+    }
+
+    pub fn set_paused(&mut self, action: PausableAction, paused: bool) {
+        match action {
+            PausableAction::Deposit => self.deposit = paused,
+            PausableAction::Claim => self.claim = paused,
+            PausableAction::Withdraw => self.withdraw = paused,
+        }
+    }
 }
 
 impl ContractInfo {
+    pub fn is_started(&self, env: &Env) -> bool {
+        if let Some(start_height) = self.start_height {
+            if env.block.height < start_height {
+                return false;
+            }
+        }
+
+        if let Some(start_time) = self.start_time {
+            if env.block.time.seconds() < start_time {
+                return false;
+            }
+        }
+        true
+    }
+
     pub fn is_expired(&self, env: &Env) -> bool {
         if let Some(end_height) = self.end_height {
             if env.block.height > end_height {
@@ -27,7 +194,7 @@ impl ContractInfo {
         }
 
         if let Some(end_time) = self.end_time {
-            if env.block.time.nanos() > end_time * 1000 {
+            if env.block.time.seconds() > end_time {
                 return true;
             }
         }
@@ -37,6 +204,144 @@ impl ContractInfo {
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 pub struct CollectionPoolInfo {
+    pub collection_id: String,
+    /// The cw721 contracts whose nfts can be staked into this pool
+    pub nft_721_contract_addrs: Vec<Addr>,
+    pub reward_per_block: Uint128,
+    /// Upcoming `reward_per_block` changes ordered by `start_block`, only used by `EmissionMode::Block`.
+    /// Entries are folded into `reward_per_block` once their start block is reached.
+    pub reward_schedule: Vec<RewardScheduleEntry>,
+    pub emission_mode: EmissionMode,
+    /// Rewards emitted per second in `EmissionMode::Time`
+    pub reward_per_second: Uint128,
+    /// Time bounds (in seconds since epoch) of the emission in `EmissionMode::Time`
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub last_reward_time: u64,
+    /// Summed stake of the staked nfts, `STAKE_PRECISION` per unit of weight before multipliers
+    pub total_nfts: Uint128,
+    /// Accumulated rewards per unit of stake, scaled up by `ACC_PER_SHARE_PRECISION`
+    pub acc_per_share: Uint128,
+    pub last_reward_block: u64,
+    /// Only set for `EmissionMode::Block`, time based pools end at `end_time`
+    pub expired_block: Option<u64>,
+    /// Blocks an nft has to stay staked before it can be withdrawn
+    #[serde(default)]
+    pub min_lock_blocks: u64,
+    /// Share of pending rewards forfeited when withdrawing nfts early, instead of a hard lock
+    #[serde(default)]
+    pub early_withdraw_penalty: Option<EarlyWithdrawPenalty>,
+    /// Multipliers for nfts staked long enough, ordered by `min_staked_blocks`
+    #[serde(default)]
+    pub loyalty_tiers: Vec<LoyaltyTier>,
+    /// Additional rewards paid next to `reward_asset`, each with its own budget
+    #[serde(default)]
+    pub reward_streams: Vec<RewardStream>,
+    #[serde(default)]
+    pub next_reward_stream_id: u64,
+    pub reward_asset: RewardAsset,
+    /// Funded rewards that have not been accrued to stakers yet
+    pub reward_balance: Uint128,
+    /// Rewards accrued to stakers so far, claimed or not
+    pub total_distributed: Uint128,
+}
+
+impl CollectionPoolInfo {
+    /// A pool is expired once `expired_block` is reached or, for time based emission, once `end_time` has passed.
+    pub fn is_expired(&self, env: &Env) -> bool {
+        if let Some(expired_block) = self.expired_block {
+            if env.block.height >= expired_block {
+                return true;
+            }
+        }
+
+        if self.emission_mode == EmissionMode::Time {
+            if let Some(end_time) = self.end_time {
+                if env.block.time.seconds() >= end_time {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Index of the highest loyalty tier reached by an nft staked at `staked_at`.
+    pub fn loyalty_tier(&self, staked_at: u64, current_block: u64) -> Option<usize> {
+        let staked_blocks = current_block.saturating_sub(staked_at);
+        self.loyalty_tiers
+            .iter()
+            .rposition(|tier| staked_blocks >= tier.min_staked_blocks)
+    }
+
+    pub fn loyalty_multiplier(&self, staked_at: u64, current_block: u64) -> Decimal {
+        match self.loyalty_tier(staked_at, current_block) {
+            Some(tier) => self.loyalty_tiers[tier].multiplier,
+            None => Decimal::one(),
+        }
+    }
+}
+
+/// A block based reward paid to the stakers of a collection pool next to its main reward.
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct RewardStream {
+    pub stream_id: u64,
+    pub reward_asset: RewardAsset,
+    pub reward_per_block: Uint128,
+    /// Nothing accrues from this block on, or from the pool's `expired_block` if that is earlier
+    pub end_block: u64,
+    pub last_reward_block: u64,
+    /// Accumulated rewards per stake, scaled up by `ACC_PER_SHARE_PRECISION`
+    pub acc_per_share: Uint128,
+    /// Funded rewards that have not been accrued to stakers yet
+    pub reward_balance: Uint128,
+    /// Rewards accrued to stakers so far, claimed or not
+    pub total_distributed: Uint128,
+    /// Set for incentive streams opened by anyone, who gets the unallocated budget back once the stream ended
+    #[serde(default)]
+    pub funder: Option<Addr>,
+    /// Set once the stream stopped accruing to the stake that still has to settle against it.
+    /// Incentive streams are removed once this reaches zero and their budget was refunded.
+    #[serde(default)]
+    pub unsettled_stake: Option<Uint128>,
+}
+
+impl RewardStream {
+    /// Streams also end once their collection pool expired, which this does not check.
+    pub fn is_ended(&self, env: &Env) -> bool {
+        env.block.height >= self.end_block
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct LoyaltyTier {
+    /// Blocks an nft has to stay staked to reach the tier
+    pub min_staked_blocks: u64,
+    /// Applied to the nft's weight, at least 1
+    pub multiplier: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct RewardScheduleEntry {
+    pub start_block: u64,
+    pub reward_per_block: Uint128,
+}
+
+/// How a collection pool emits its rewards.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EmissionMode {
+    /// `reward_per_block` accrues for every block
+    #[default]
+    Block,
+    /// `reward_per_second` accrues from `env.block.time` between `start_time` and `end_time`
+    Time,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct LegacyCollectionPoolInfo {
     pub collection_id: String,
     pub reward_per_block: Uint128,
     pub total_nfts: Uint128,
@@ -45,13 +350,89 @@ pub struct CollectionPoolInfo {
     pub expired_block: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct EarlyWithdrawPenalty {
+    /// Blocks after staking during which withdrawing an nft, or being paid rewards while it is staked, is penalized
+    pub lock_blocks: u64,
+    /// Share of the staker's pending rewards that is forfeited, reward streams are not penalized
+    pub penalty_rate: Decimal,
+    /// Receives the forfeited rewards, defaults to the contract's `fee_collector`.
+    /// They are split among the remaining stakers when neither is set.
+    pub treasury: Option<Addr>,
+}
+
+/// The token a collection pool pays its rewards in.
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RewardAsset {
+    Native { denom: String },
+    Cw20 { contract_addr: Addr },
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 pub struct StakerInfo {
+    /// Summed weight of `staked_tokens` in units of `STAKE_PRECISION`, boosted by their loyalty tiers and multiplied
+    /// by the best completed set bonus. Loyalty tiers are only picked up when the stake is recalculated.
     pub total_staked: Uint128,
     pub reward_debt: Uint128,
     pub pending: Uint128,
     pub total_earned: Uint128,
     pub staked_tokens: Vec<CollectionStakedTokenInfo>,
+    /// Ids of the set bonuses completed as of the staker's last deposit or withdrawal
+    #[serde(default)]
+    pub set_bonuses: Vec<String>,
+    /// The staker's position in each of the pool's reward streams
+    #[serde(default)]
+    pub stream_rewards: Vec<StakerStreamReward>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct StakerStreamReward {
+    pub stream_id: u64,
+    pub reward_debt: Uint128,
+    pub pending: Uint128,
+    pub total_earned: Uint128,
+    /// Set once the stake settled against the stream after it stopped accruing,
+    /// so `pending` can still be paid once the stream was removed
+    #[serde(default)]
+    pub reward_asset: Option<RewardAsset>,
+}
+
+/// Multiplies the stake of stakers holding a complete set of nfts in a collection pool.
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct SetBonus {
+    pub set_id: String,
+    pub requirement: SetRequirement,
+    /// Applied to the staker's summed nft weight, at least 1
+    pub multiplier: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SetRequirement {
+    /// At least one staked nft from each contract
+    Contracts { contract_addrs: Vec<Addr> },
+    /// At least one staked nft of `contract_addr` from each group of token ids
+    TokenGroups {
+        contract_addr: Addr,
+        groups: Vec<Vec<String>>,
+    },
+}
+
+impl SetBonus {
+    pub fn is_completed(&self, staked_tokens: &[CollectionStakedTokenInfo]) -> bool {
+        match &self.requirement {
+            SetRequirement::Contracts { contract_addrs } => contract_addrs
+                .iter()
+                .all(|addr| staked_tokens.iter().any(|token| token.contract_addr.eq(addr))),
+            SetRequirement::TokenGroups { contract_addr, groups } => groups
+                .iter()
+                .all(|group| staked_tokens.iter().any(|token| token.contract_addr.eq(contract_addr) && group.contains(&token.token_id))),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -59,6 +440,16 @@ pub struct StakerInfo {
 pub struct CollectionStakedTokenInfo {
     pub token_id: String,
     pub contract_addr: Addr,
+    /// Block height the nft was staked at
+    #[serde(default)]
+    pub staked_at: u64,
+    /// Weight the nft was staked with, later weight changes don't apply until it is staked again
+    #[serde(default = "default_token_weight")]
+    pub weight: Uint128,
+}
+
+fn default_token_weight() -> Uint128 {
+    DEFAULT_TOKEN_WEIGHT
 }
 
 